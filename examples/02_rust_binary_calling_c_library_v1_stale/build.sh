@@ -23,12 +23,12 @@ ar rc $OUT_DIR/libexample.a $OUT_DIR/impl_lib.o
 # build bin example
 rustc --edition=2021 --crate-name example --crate-type lib \
       --emit=dep-info,metadata,link \
-      -l example -L out \
+      -L out \
       --out-dir out \
       $GEN_DIR/example.rs
 
 rustc --edition=2021 --crate-name example --crate-type bin \
       --extern example=out/libexample.rlib \
-      -l example -L out \
+      -L out \
       --out-dir out \
       impl_bin.rs
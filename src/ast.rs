@@ -3,12 +3,69 @@ use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct ApiDefn {
+    pub library: Option<Library>,
+    pub symbol_prefix: Option<SymbolPrefix>,
     pub symbols: HashMap<String, Rc<Decl>>,
     pub apis: Apis,
     pub decls: Vec<Rc<Decl>>,
     pub suffix: Skip,
 }
 
+// The library described by a .dy file
+#[derive(Debug)]
+pub struct Library {
+    pub prefix: Skip,
+    pub name: String,
+    pub version: Option<String>,
+    pub soname: Option<String>,
+    pub link: Option<String>,
+}
+
+impl Library {
+    // Prefix for the macros emitted for the library: e.g. "EXAMPLE"
+    pub fn macro_prefix(&self) -> String {
+        self.name.to_uppercase()
+    }
+
+    // Names of the macros the C header defines for the library
+    pub fn macro_names(&self) -> Vec<String> {
+        let prefix = self.macro_prefix();
+        let mut names = vec![format!("{}_H", prefix), format!("{}_API_EXPORT", prefix)];
+        if self.version.is_some() {
+            names.push(format!("{}_VERSION", prefix));
+        }
+        if self.soname.is_some() {
+            names.push(format!("{}_SONAME", prefix));
+        }
+        names
+    }
+}
+
+// The prefix applied to the names declared in a .dy file: e.g. "example_"
+#[derive(Debug)]
+pub struct SymbolPrefix {
+    pub prefix: Skip,
+    pub name: String,
+}
+
+impl SymbolPrefix {
+    // Constants are macros in C, so their prefix is upper-cased: e.g. "EXAMPLE_"
+    pub fn macro_prefix(&self) -> String {
+        self.name.to_uppercase()
+    }
+
+    // Removes the prefix from a declared name, if it has it
+    pub fn strip<'a>(&self, name: &'a str) -> Option<&'a str> {
+        name.strip_prefix(&self.name)
+            .or_else(|| name.strip_prefix(&self.macro_prefix()))
+    }
+
+    // Name of the module of unprefixed names: e.g. "example"
+    pub fn module_name(&self) -> &str {
+        self.name.trim_end_matches('_')
+    }
+}
+
 #[derive(Debug)]
 pub struct Api {
     pub name: String,
@@ -28,6 +85,12 @@ pub struct Apis {
     pub apis: Vec<Api>,
 }
 
+impl Default for Apis {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Apis {
     pub fn new() -> Self {
         Self {
@@ -70,11 +133,24 @@ impl Apis {
 #[derive(Debug, Clone)]
 pub struct Skip(pub Vec<SkipElem>);
 
+impl Skip {
+    // The skip without any leading whitespace
+    pub fn trim_start(&self) -> Skip {
+        let mut elems = self.0.clone();
+        if let Some(SkipElem::Whitespace(w)) = elems.first_mut() {
+            *w = w.trim_start().to_string();
+        }
+        Skip(elems)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum SkipElem {
     Whitespace(String),
     LineComment(String),
     BlockComment(String),
+    // "///" or "/** */": attached to the following decl, field or arg if any
+    DocComment(Vec<String>),
 }
 
 #[derive(Debug)]
@@ -83,6 +159,14 @@ pub enum Decl {
     Struct(StructDecl),
     Opaque(OpaqueDecl),
     Const(ConstDecl),
+    Enum(EnumDecl),
+    Flags(FlagsDecl),
+    Union(UnionDecl),
+    Variant(VariantDecl),
+    Callback(CallbackDecl),
+    Alias(AliasDecl),
+    Errors(ErrorsDecl),
+    Import(ImportDecl),
 }
 
 impl Decl {
@@ -92,6 +176,96 @@ impl Decl {
             Decl::Struct(decl) => decl.name.clone(),
             Decl::Opaque(decl) => decl.name.clone(),
             Decl::Const(decl) => decl.name.clone(),
+            Decl::Enum(decl) => decl.name.clone(),
+            Decl::Flags(decl) => decl.name.clone(),
+            Decl::Union(decl) => decl.name.clone(),
+            Decl::Variant(decl) => decl.name.clone(),
+            Decl::Callback(decl) => decl.name.clone(),
+            Decl::Alias(decl) => decl.name.clone(),
+            Decl::Errors(decl) => decl.name.clone(),
+            Decl::Import(decl) => decl.path.clone(),
+        }
+    }
+
+    // Names of the macros the C header defines for the decl: e.g.
+    // "EXAMPLE_MODE_A" for enumerator "A" of "example_mode"
+    pub fn macro_names(&self) -> Vec<String> {
+        let scoped = |scope: &str, name: &str| format!("{}_{}", scope.to_uppercase(), name);
+        match self {
+            Decl::Const(decl) => vec![decl.name.clone()],
+            Decl::Enum(decl) => (decl.enumerators.iter())
+                .map(|e| scoped(&decl.name, &e.name))
+                .collect(),
+            Decl::Flags(decl) => (decl.flags.iter())
+                .map(|f| scoped(&decl.name, &f.name))
+                .collect(),
+            Decl::Errors(decl) => (decl.errors.iter())
+                .map(|e| scoped(&decl.name, &e.name))
+                .collect(),
+            // tag constants are upper-cased too
+            Decl::Variant(decl) => (decl.cases.iter())
+                .map(|c| scoped(&decl.name, &c.name.to_uppercase()))
+                .collect(),
+            Decl::Struct(decl) if decl.has_defaults() => {
+                vec![format!("{}_INIT", decl.name.to_uppercase())]
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn docs(&self) -> &[String] {
+        match self {
+            Decl::Fn(decl) => &decl.docs,
+            Decl::Struct(decl) => &decl.docs,
+            Decl::Opaque(decl) => &decl.docs,
+            Decl::Const(decl) => &decl.docs,
+            Decl::Enum(decl) => &decl.docs,
+            Decl::Flags(decl) => &decl.docs,
+            Decl::Union(decl) => &decl.docs,
+            Decl::Variant(decl) => &decl.docs,
+            Decl::Callback(decl) => &decl.docs,
+            Decl::Alias(decl) => &decl.docs,
+            Decl::Errors(decl) => &decl.docs,
+            Decl::Import(decl) => &decl.docs,
+        }
+    }
+
+    pub fn attrs(&self) -> &[Attribute] {
+        match self {
+            Decl::Fn(decl) => &decl.attrs,
+            Decl::Struct(decl) => &decl.attrs,
+            Decl::Opaque(decl) => &decl.attrs,
+            Decl::Const(decl) => &decl.attrs,
+            Decl::Enum(decl) => &decl.attrs,
+            Decl::Flags(decl) => &decl.attrs,
+            Decl::Union(decl) => &decl.attrs,
+            Decl::Variant(decl) => &decl.attrs,
+            Decl::Callback(decl) => &decl.attrs,
+            Decl::Alias(decl) => &decl.attrs,
+            Decl::Errors(decl) => &decl.attrs,
+            Decl::Import(decl) => &decl.attrs,
+        }
+    }
+
+    // All types used directly by the decl (not including integer reprs)
+    pub fn types(&self) -> Vec<&Type> {
+        match self {
+            Decl::Fn(decl) => signature_types(&decl.args, &decl.ret),
+            Decl::Struct(decl) => decl.fields.iter().map(|f| &f.typ).collect(),
+            Decl::Union(decl) => decl.fields.iter().map(|f| &f.typ).collect(),
+            Decl::Variant(decl) => decl
+                .cases
+                .iter()
+                .filter_map(|c| c.payload.as_ref())
+                .collect(),
+            Decl::Callback(decl) => decl.typ.types(),
+            Decl::Alias(decl) => vec![&decl.typ],
+            Decl::Opaque(_)
+            | Decl::Const(_)
+            | Decl::Enum(_)
+            | Decl::Flags(_)
+            | Decl::Errors(_)
+            | Decl::Import(_) => vec![],
         }
     }
 }
@@ -99,39 +273,445 @@ impl Decl {
 #[derive(Debug)]
 pub struct FuncDecl {
     pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
     pub name: String,
     pub args: Vec<Field>,
     pub ret: ReturnType,
+    pub ret_docs: Vec<String>,
     pub version: Version,
 }
 
+impl FuncDecl {
+    // The error domain of the status returned: "#[returns_status(example_err)]"
+    pub fn returns_status(&self) -> Option<&str> {
+        match find_attr(&self.attrs, "returns_status")?.args.as_slice() {
+            [AttrArg {
+                key: None,
+                val: AttrValue::Ident(name),
+            }] => Some(name),
+            _ => None,
+        }
+    }
+
+    // The opaque type of a new object owned by the caller: "#[constructor]"
+    pub fn constructs(&self) -> Option<&str> {
+        find_attr(&self.attrs, "constructor")?;
+        match &self.ret {
+            ReturnType::Some(Type::Pointer(_, _, pointee)) => match pointee.as_ref() {
+                Type::Base(BaseType::Struct(name)) => Some(name),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // The opaque type of the object released: "#[destructor(example_ctx)]"
+    pub fn destructs(&self) -> Option<&str> {
+        match find_attr(&self.attrs, "destructor")?.args.as_slice() {
+            [AttrArg {
+                key: None,
+                val: AttrValue::Ident(name),
+            }] => Some(name),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub struct Version(pub u64);
 
 #[derive(Debug)]
 pub struct StructDecl {
     pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+impl StructDecl {
+    // Structs with field defaults, including those of nested structs, get
+    // initializers
+    pub fn has_defaults(&self) -> bool {
+        self.fields.iter().any(|f| f.default.is_some())
+    }
+
+    pub fn has_bits(&self) -> bool {
+        self.fields.iter().any(|f| f.bits.is_some())
+    }
+
+    pub fn packed(&self) -> bool {
+        find_attr(&self.attrs, "packed").is_some()
+    }
+
+    // Explicit alignment in bytes: "#[align(N)]"
+    pub fn align(&self) -> Option<i128> {
+        match find_attr(&self.attrs, "align")?.args.as_slice() {
+            [AttrArg {
+                key: None,
+                val: AttrValue::Int(lit),
+            }] => Some(lit.val),
+            _ => None,
+        }
+    }
+
+    // Names of the fields as emitted: "#[pad]" fields are renamed to "_pad0",
+    // "_pad1", etc so that a struct may have several of them
+    pub fn field_names(&self) -> Vec<String> {
+        let mut pads = 0;
+        let mut names = vec![];
+        for f in &self.fields {
+            if f.is_pad() {
+                names.push(format!("_pad{}", pads));
+                pads += 1;
+            } else {
+                names.push(f.name.clone());
+            }
+        }
+        names
+    }
+}
+
+#[derive(Debug)]
+pub struct UnionDecl {
+    pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
     pub name: String,
     pub fields: Vec<Field>,
 }
 
+#[derive(Debug)]
+pub struct VariantDecl {
+    pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
+    pub name: String,
+    pub tag: BaseType,
+    pub cases: Vec<VariantCase>,
+}
+
+#[derive(Debug)]
+pub struct VariantCase {
+    pub name: String,
+    pub payload: Option<Type>,
+}
+
+impl VariantDecl {
+    pub fn has_payloads(&self) -> bool {
+        self.cases.iter().any(|c| c.payload.is_some())
+    }
+}
+
+// An imported .dy file: its decls are emitted with it rather than here
+#[derive(Debug)]
+pub struct ImportDecl {
+    pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
+    pub path: String,
+    pub defn: ApiDefn,
+}
+
+impl ImportDecl {
+    // The name of the imported file without directories or extension
+    pub fn stem(&self) -> &str {
+        let file = self.path.rsplit('/').next().unwrap();
+        file.strip_suffix(".dy").unwrap_or(file)
+    }
+}
+
 #[derive(Debug)]
 pub struct OpaqueDecl {
     pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
     pub name: String,
+    // Declared storage, so that callers can allocate the object themselves:
+    // "opaque example_lock(size = 64, align = 8);". Changing it is an ABI break
+    pub size: Option<ConstExpr>,
+    pub align: Option<i128>,
+}
+
+impl OpaqueDecl {
+    // The fns counting references to an object:
+    // "#[refcounted(retain = example_buf_ref, release = example_buf_unref)]"
+    pub fn retain(&self) -> Option<&str> {
+        self.refcounted("retain")
+    }
+
+    pub fn release(&self) -> Option<&str> {
+        self.refcounted("release")
+    }
+
+    fn refcounted(&self, key: &str) -> Option<&str> {
+        find_attr(&self.attrs, "refcounted")?
+            .args
+            .iter()
+            .find_map(|arg| match arg {
+                AttrArg {
+                    key: Some(k),
+                    val: AttrValue::Ident(name),
+                } if k == key => Some(name.as_str()),
+                _ => None,
+            })
+    }
 }
 
 #[derive(Debug)]
 pub struct ConstDecl {
     pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
     pub name: String,
-    pub val: u64,
+    pub val: ConstValue,
 }
 
 #[derive(Debug)]
+pub enum ConstValue {
+    Int(BaseType, ConstExpr),
+    Str(String),
+}
+
+// Constant expression: evaluated at parse time, but the expression itself is
+// kept so that emitters can preserve the symbolic form
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConstExpr {
+    pub expr: Expr,
+    pub val: i128,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Lit(IntLit),
+    Const(String, BaseType),
+    Neg(Box<Expr>),
+    Paren(Box<Expr>),
+    Binary(Box<Expr>, BinOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Shl,
+    And,
+    Or,
+}
+
+impl BinOp {
+    // Mixing shifts or bitwise ops with other ops without parentheses is
+    // legal but draws compiler warnings, so emitters parenthesize those
+    pub fn needs_parens_within(&self, outer: BinOp) -> bool {
+        let is_arith = |op: BinOp| matches!(op, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div);
+        *self != outer && !(is_arith(*self) && is_arith(outer))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Shl => "<<",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+        }
+    }
+}
+
+// Integer literal: the text is kept as written (e.g. hex) for emitting
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntLit {
+    pub val: i128,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub struct EnumDecl {
+    pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
+    pub name: String,
+    pub repr: BaseType,
+    pub enumerators: Vec<Enumerator>,
+}
+
+#[derive(Debug)]
+pub struct FlagsDecl {
+    pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
+    pub name: String,
+    pub repr: BaseType,
+    pub flags: Vec<Enumerator>,
+}
+
+#[derive(Debug)]
+pub struct Enumerator {
+    pub name: String,
+    pub val: IntLit,
+}
+
+// An error domain: status codes with messages, where 0 is success
+#[derive(Debug)]
+pub struct ErrorsDecl {
+    pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
+    pub name: String,
+    pub repr: BaseType,
+    pub errors: Vec<ErrorCode>,
+}
+
+impl ErrorsDecl {
+    // Name of the function that describes a status: e.g. "example_strerror"
+    // for "example_err"
+    pub fn strerror_name(&self) -> String {
+        let stem = ["_errors", "_error", "_err"]
+            .iter()
+            .find_map(|suffix| self.name.strip_suffix(suffix))
+            .unwrap_or(&self.name);
+        format!("{}_strerror", stem)
+    }
+}
+
+#[derive(Debug)]
+pub struct ErrorCode {
+    pub name: String,
+    pub val: IntLit,
+    pub msg: String,
+}
+
+#[derive(Debug)]
+pub struct CallbackDecl {
+    pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
+    pub name: String,
+    pub typ: FnType,
+}
+
+// A name for another type: e.g. "type example_len = u32;"
+#[derive(Debug)]
+pub struct AliasDecl {
+    pub prefix: Skip,
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
+    pub name: String,
+    pub typ: Type,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Field {
+    pub docs: Vec<String>,
+    pub attrs: Vec<Attribute>,
     pub name: String,
     pub typ: Type,
+    pub default: Option<FieldDefault>,
+    // Named bit ranges of an integer struct field: "flags: u32 { valid: 1 }"
+    pub bits: Option<Bits>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FieldDefault {
+    // A value, with the field's resolved integer type: "num: u8 = 0"
+    Int(BaseType, ConstExpr),
+    // A field whose (resolved) struct type has defaults of its own
+    Struct(String),
+}
+
+// The bit ranges are accessed with explicit shifts and masks, as the layout of
+// C bitfields is unspecified
+#[derive(Debug, PartialEq)]
+pub struct Bits {
+    // the resolved unsigned integer type of the field
+    pub repr: BaseType,
+    pub ranges: Vec<BitRange>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BitRange {
+    pub name: String,
+    pub offset: u32,
+    pub width: u32,
+}
+
+impl BitRange {
+    // The mask of the range, before shifting to its offset
+    pub fn mask(&self) -> u64 {
+        u64::MAX >> (64 - self.width)
+    }
+}
+
+// Which way data flows through a pointer arg: "#[in]", "#[out]" or "#[inout]"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+    InOut,
+}
+
+impl Field {
+    // Reserved padding in a struct: "#[pad] reserved: [u8; N]"
+    pub fn is_pad(&self) -> bool {
+        find_attr(&self.attrs, "pad").is_some()
+    }
+
+    pub fn direction(&self) -> Option<Direction> {
+        self.attrs.iter().find_map(|a| match a.name.as_str() {
+            "in" => Some(Direction::In),
+            "out" => Some(Direction::Out),
+            "inout" => Some(Direction::InOut),
+            _ => None,
+        })
+    }
+
+    // The pointer arg that this arg is the length of: "#[len_of(buf)]"
+    pub fn len_of(&self) -> Option<&str> {
+        match find_attr(&self.attrs, "len_of")?.args.as_slice() {
+            [AttrArg {
+                key: None,
+                val: AttrValue::Ident(ptr),
+            }] => Some(ptr),
+            _ => None,
+        }
+    }
+}
+
+// An attribute: "#[name]" or "#[name(key = value, ...)]". Namespaced names
+// (e.g. "x_myteam::foo") are left for downstream tools to interpret
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub args: Vec<AttrArg>,
+}
+
+impl Attribute {
+    pub fn is_namespaced(&self) -> bool {
+        self.name.contains("::")
+    }
+}
+
+pub fn find_attr<'a>(attrs: &'a [Attribute], name: &str) -> Option<&'a Attribute> {
+    attrs.iter().find(|a| a.name == name)
+}
+
+// An attribute argument: "key = value", or just "value"
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttrArg {
+    pub key: Option<String>,
+    pub val: AttrValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrValue {
+    Int(IntLit),
+    Str(String),
+    Ident(String),
 }
 
 #[derive(Debug, PartialEq)]
@@ -142,12 +722,79 @@ pub enum ReturnType {
 
 #[derive(Debug, PartialEq)]
 pub enum Type {
-    Pointer(Box<Type>),
-    Array(Box<Type>, u64),
+    Pointer(Mutability, Nullability, Box<Type>),
+    Array(Box<Type>, ConstExpr),
+    FnPtr(Box<FnType>),
     Base(BaseType),
 }
 
+// Whether the pointee may be modified through a pointer. A bare `*T` is
+// `*mut T`, so the two spellings are the same type. Changing between const
+// and mut changes the signature of anything that uses the type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mutability {
+    Const,
+    Mut,
+}
+
+// Whether a pointer may be null: unannotated pointers make no promise either way
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Nullability {
+    Unspecified,
+    NonNull,
+    Nullable,
+}
+
 #[derive(Debug, PartialEq)]
+pub struct FnType {
+    pub args: Vec<Field>,
+    pub ret: ReturnType,
+    pub ret_docs: Vec<String>,
+}
+
+impl FnType {
+    pub fn types(&self) -> Vec<&Type> {
+        signature_types(&self.args, &self.ret)
+    }
+}
+
+fn signature_types<'a>(args: &'a [Field], ret: &'a ReturnType) -> Vec<&'a Type> {
+    let mut types: Vec<&Type> = args.iter().map(|f| &f.typ).collect();
+    if let ReturnType::Some(t) = ret {
+        types.push(t);
+    }
+    types
+}
+
+impl Type {
+    // Returns true if the type or any type within it matches the predicate
+    pub fn any(&self, pred: &dyn Fn(&Type) -> bool) -> bool {
+        if pred(self) {
+            return true;
+        }
+        match self {
+            Type::Pointer(_, _, subtype) => subtype.any(pred),
+            Type::Array(subtype, _) => subtype.any(pred),
+            Type::FnPtr(fntype) => fntype.types().iter().any(|t| t.any(pred)),
+            Type::Base(_) => false,
+        }
+    }
+
+    // Returns true if any base type within the type matches the predicate
+    pub fn any_base(&self, pred: &dyn Fn(&BaseType) -> bool) -> bool {
+        self.any(&|t| matches!(t, Type::Base(base) if pred(base)))
+    }
+
+    // Nullability of the type if it's a pointer
+    pub fn nullability(&self) -> Option<Nullability> {
+        match self {
+            Type::Pointer(_, nullability, _) => Some(*nullability),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum BaseType {
     Struct(String),
     U8,
@@ -158,4 +805,20 @@ pub enum BaseType {
     I32,
     U64,
     I64,
+    Bool,
+    F32,
+    F64,
+    USize,
+    ISize,
+    CChar,
+    CSChar,
+    CUChar,
+    CShort,
+    CUShort,
+    CInt,
+    CUInt,
+    CLong,
+    CULong,
+    CLongLong,
+    CULongLong,
 }
@@ -14,6 +14,10 @@ struct Args {
     /// Type of ouput to generate
     #[arg(value_enum, short, long)]
     emit: Emit,
+
+    /// Rust only: also emit a module with the names declared without their prefix
+    #[arg(long)]
+    rust_unprefixed_module: bool,
 }
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -42,7 +46,10 @@ fn run() -> std::result::Result<(), String> {
         err => panic!("BUG: Unexpected error: {:?}", err),
     };
 
-    let options = Default::default();
+    let options = emit::Options {
+        rust_unprefixed_module: args.rust_unprefixed_module,
+        ..Default::default()
+    };
 
     match args.emit {
         Emit::Ast => println!("{:#?}", ast),
@@ -1,4 +1,4 @@
-use super::ast::ApiDefn;
+use super::ast::{ApiDefn, Library};
 
 pub mod c;
 pub mod rust;
@@ -11,12 +11,15 @@ pub enum Language {
 #[derive(Clone)]
 pub struct Options {
     pub api_forward_to_latest: bool,
+    // Rust only: a module re-exporting the declarations without their prefix
+    pub rust_unprefixed_module: bool,
 }
 
 impl Default for Options {
     fn default() -> Self {
         Self {
             api_forward_to_latest: true,
+            rust_unprefixed_module: false,
         }
     }
 }
@@ -29,3 +32,20 @@ pub fn emit(api: &ApiDefn, lang: Language, options: Options) -> String {
     }
     out
 }
+
+// The comment both languages start with: a library names itself in it
+fn banner(lib: Option<&Library>) -> String {
+    let mut banner = "\
+/*******************************************************************************
+ * Autogenerated by Dandiya API Generator
+"
+    .to_string();
+    if let Some(lib) = lib {
+        banner += &format!(" * Library: {}", lib.name);
+        if let Some(version) = &lib.version {
+            banner += &format!(" {}", version);
+        }
+        banner += "\n";
+    }
+    banner + " ******************************************************************************/\n"
+}
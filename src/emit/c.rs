@@ -1,27 +1,6 @@
 use crate::ast::*;
 use crate::emit::Options;
 
-pub const PREAMBLE: &str = "\
-/*******************************************************************************
- * Autogenerated by Dandiya API Generator
- ******************************************************************************/
-#pragma once
-#include <stdint.h>
-
-#ifndef DANDIYA_API_EXPORT
-#  if defined(__GNUC__) || defined(__clang__)
-#    define DANDIYA_API_EXPORT __attribute__((visibility(\"default\")))
-#  else
-#    error Unsupported compiler
-#  endif
-#endif
-
-#ifdef __cplusplus
-extern \"C\" {
-#endif
-
-";
-
 pub const POSTAMBLE: &str = "
 #ifdef __cplusplus
 }
@@ -34,56 +13,340 @@ pub const API_HEADER: &str = "
  ******************************************************************************/
 ";
 
-// Returns (front-part, back-part)
-fn type_str(t: &Type) -> (String, String) {
+// Returns (front-part, back-part): a declarator is the name wrapped by these.
+// The parts closest to the name bind tightest: e.g. "void (*" name "[4])(void)"
+// Once a header uses nullability clang expects it on every pointer, so with
+// null_unspecified set the unannotated ones are marked as such.
+fn type_str(t: &Type, null_unspecified: bool) -> (String, String) {
     match t {
-        Type::Pointer(subtype) => {
-            let (front, back) = type_str(subtype);
-            (front + "*", back)
+        Type::Pointer(mutability, nullability, subtype) => {
+            let (front, back) = type_str(subtype, null_unspecified);
+            // "const T*" reads best, but when the pointee is itself a pointer
+            // the qualifier has to follow the '*' it applies to
+            let front = match (mutability, subtype.as_ref()) {
+                (Mutability::Mut, _) => front + "*",
+                (Mutability::Const, Type::Base(_)) => format!("const {}*", front),
+                (Mutability::Const, _) => front + " const*",
+            };
+            let front = match nullability {
+                Nullability::Unspecified if null_unspecified => front + " DANDIYA_NULL_UNSPECIFIED",
+                Nullability::Unspecified => front,
+                Nullability::NonNull => front + " DANDIYA_NONNULL",
+                Nullability::Nullable => front + " DANDIYA_NULLABLE",
+            };
+            (front, back)
         }
         Type::Array(subtype, len) => {
-            let (front, back) = type_str(subtype);
-            (front, format!("{}[{}]", back, len))
+            let (front, back) = type_str(subtype, null_unspecified);
+            // lengths are evaluated as u64, so compound lengths must not be int
+            let len = constexpr_str(len, int_suffix(&BaseType::U64));
+            (front, format!("[{}]{}", len, back))
         }
-        Type::Base(base) => {
-            let s = match base {
-                BaseType::Struct(s) => format!("{}_t", s),
-                BaseType::U8 => "uint8_t".to_string(),
-                BaseType::I8 => "int8_t".to_string(),
-                BaseType::U16 => "uint16_t".to_string(),
-                BaseType::I16 => "int16_t".to_string(),
-                BaseType::U32 => "uint32_t".to_string(),
-                BaseType::I32 => "int32_t".to_string(),
-                BaseType::U64 => "uint64_t".to_string(),
-                BaseType::I64 => "int64_t".to_string(),
-            };
-            (s, "".to_string())
+        Type::FnPtr(fntype) => fntype_str(fntype, null_unspecified),
+        Type::Base(base) => (base_str(base), "".to_string()),
+    }
+}
+
+fn fntype_str(fntype: &FnType, null_unspecified: bool) -> (String, String) {
+    let (front, back) = match &fntype.ret {
+        ReturnType::None => ("void".to_string(), "".to_string()),
+        ReturnType::Some(t) => type_str(t, null_unspecified),
+    };
+    let args = format!("({})", args_str(&fntype.args, null_unspecified));
+    let ptr = if null_unspecified {
+        "(* DANDIYA_NULL_UNSPECIFIED"
+    } else {
+        "(*"
+    };
+    (
+        join_decl((front, "".to_string()), ptr),
+        format!("){}{}", args, back),
+    )
+}
+
+fn decl_str(t: &Type, name: &str, null_unspecified: bool) -> String {
+    join_decl(type_str(t, null_unspecified), name)
+}
+
+// Pointers taken or returned by the generated inlines are never null, which
+// only needs saying once the header uses nullability
+fn inline_ptr(mutability: Mutability, base: BaseType, null_unspecified: bool) -> Type {
+    let nullability = if null_unspecified {
+        Nullability::NonNull
+    } else {
+        Nullability::Unspecified
+    };
+    Type::Pointer(mutability, nullability, Box::new(Type::Base(base)))
+}
+
+fn join_decl((front, back): (String, String), name: &str) -> String {
+    // no space needed inside a "(*" declarator
+    if front.trim_end_matches('*').ends_with('(') {
+        format!("{}{}{}", front, name, back)
+    } else {
+        format!("{} {}{}", front, name, back)
+    }
+}
+
+fn base_str(base: &BaseType) -> String {
+    match base {
+        BaseType::Struct(s) => format!("{}_t", s),
+        BaseType::U8 => "uint8_t".to_string(),
+        BaseType::I8 => "int8_t".to_string(),
+        BaseType::U16 => "uint16_t".to_string(),
+        BaseType::I16 => "int16_t".to_string(),
+        BaseType::U32 => "uint32_t".to_string(),
+        BaseType::I32 => "int32_t".to_string(),
+        BaseType::U64 => "uint64_t".to_string(),
+        BaseType::I64 => "int64_t".to_string(),
+        BaseType::Bool => "bool".to_string(),
+        BaseType::F32 => "float".to_string(),
+        BaseType::F64 => "double".to_string(),
+        BaseType::USize => "size_t".to_string(),
+        BaseType::ISize => "ptrdiff_t".to_string(),
+        BaseType::CChar => "char".to_string(),
+        BaseType::CSChar => "signed char".to_string(),
+        BaseType::CUChar => "unsigned char".to_string(),
+        BaseType::CShort => "short".to_string(),
+        BaseType::CUShort => "unsigned short".to_string(),
+        BaseType::CInt => "int".to_string(),
+        BaseType::CUInt => "unsigned int".to_string(),
+        BaseType::CLong => "long".to_string(),
+        BaseType::CULong => "unsigned long".to_string(),
+        BaseType::CLongLong => "long long".to_string(),
+        BaseType::CULongLong => "unsigned long long".to_string(),
+    }
+}
+
+// Nullability qualifiers are only understood by clang: other compilers
+// get the nonnull function attributes instead
+const NULLABILITY_MACROS: &str = "\
+#ifndef DANDIYA_NONNULL
+#  if defined(__clang__)
+#    define DANDIYA_NONNULL _Nonnull
+#    define DANDIYA_NULLABLE _Nullable
+#    define DANDIYA_NULL_UNSPECIFIED _Null_unspecified
+#  else
+#    define DANDIYA_NONNULL
+#    define DANDIYA_NULLABLE
+#    define DANDIYA_NULL_UNSPECIFIED
+#  endif
+#endif
+
+";
+
+fn uses_type(defn: &ApiDefn, pred: &dyn Fn(&Type) -> bool) -> bool {
+    defn.decls
+        .iter()
+        .any(|d| d.types().iter().any(|t| t.any(pred)))
+}
+
+fn uses_nullability(defn: &ApiDefn) -> bool {
+    uses_type(
+        defn,
+        &|t| matches!(t.nullability(), Some(n) if n != Nullability::Unspecified),
+    )
+}
+
+// The start of the header, with what the file needs: a library names itself
+// in the banner, gets an include guard rather than "#pragma once", and
+// prefixes the export macro with its name, and only <stdint.h> is included by
+// default
+pub fn preamble(defn: &ApiDefn) -> String {
+    let lib = defn.library.as_ref();
+    let uses = |pred: &dyn Fn(&BaseType) -> bool| {
+        uses_type(defn, &|t| matches!(t, Type::Base(base) if pred(base)))
+    };
+    let mut preamble = crate::emit::banner(lib);
+    match lib {
+        Some(lib) => {
+            let prefix = lib.macro_prefix();
+            preamble += &format!("#ifndef {}_H\n#define {}_H\n", prefix, prefix);
+        }
+        None => preamble += "#pragma once\n",
+    }
+    preamble += "#include <stdint.h>\n";
+    if uses(&|b| *b == BaseType::Bool) {
+        preamble += "#include <stdbool.h>\n";
+    }
+    if uses(&|b| matches!(b, BaseType::USize | BaseType::ISize)) {
+        preamble += "#include <stddef.h>\n";
+    }
+    preamble += "\n";
+    preamble += &export_macro_defn(&export_macro(lib));
+    preamble += "\n";
+    if let Some(lib) = lib {
+        preamble += &library_macros(lib);
+    }
+    if uses_nullability(defn) {
+        preamble += NULLABILITY_MACROS;
+    }
+    preamble += "#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n";
+    preamble
+}
+
+fn export_macro_defn(export: &str) -> String {
+    format!(
+        "\
+#ifndef {export}
+#  if defined(__GNUC__) || defined(__clang__)
+#    define {export} __attribute__((visibility(\"default\")))
+#  else
+#    error Unsupported compiler
+#  endif
+#endif
+"
+    )
+}
+
+fn library_macros(lib: &Library) -> String {
+    let prefix = lib.macro_prefix();
+    let mut macros = String::new();
+    if let Some(version) = &lib.version {
+        macros += &format!("#define {}_VERSION \"{}\"\n", prefix, version);
+    }
+    if let Some(soname) = &lib.soname {
+        macros += &format!("#define {}_SONAME \"{}\"\n", prefix, soname);
+    }
+    if !macros.is_empty() {
+        macros += "\n";
+    }
+    macros
+}
+
+fn postamble(defn: &ApiDefn) -> String {
+    match &defn.library {
+        Some(lib) => format!("{}\n#endif /* {}_H */\n", POSTAMBLE, lib.macro_prefix()),
+        None => POSTAMBLE.to_string(),
+    }
+}
+
+fn export_macro(lib: Option<&Library>) -> String {
+    match lib {
+        Some(lib) => format!("{}_API_EXPORT", lib.macro_prefix()),
+        None => "DANDIYA_API_EXPORT".to_string(),
+    }
+}
+
+// Literals within compound expressions get a suffix so that C doesn't evaluate
+// the expression as an int when the declared type is wider
+fn int_suffix(typ: &BaseType) -> &'static str {
+    match typ {
+        BaseType::U32 => "U",
+        BaseType::U64 => "ULL",
+        BaseType::I64 => "LL",
+        _ => "",
+    }
+}
+
+// The type a literal suffix gives, so that narrower constants can be cast to match
+fn suffix_type(suffix: &str) -> Option<&'static str> {
+    match suffix {
+        "ULL" => Some("uint64_t"),
+        "LL" => Some("int64_t"),
+        _ => None,
+    }
+}
+
+// C has no negative literals, and 9223372036854775808 doesn't fit in a signed
+// type, so the minimum i64 has to be written as an expression
+fn intlit_str(lit: &IntLit, suffix: &str) -> String {
+    if lit.val == i64::MIN as i128 {
+        "(-9223372036854775807LL - 1)".to_string()
+    } else {
+        format!("{}{}", lit.text, suffix)
+    }
+}
+
+fn constexpr_str(e: &ConstExpr, suffix: &str) -> String {
+    match &e.expr {
+        Expr::Lit(lit) => intlit_str(lit, ""),
+        Expr::Const(name, _) => name.clone(),
+        expr => expr_str(expr, suffix),
+    }
+}
+
+fn expr_str(e: &Expr, suffix: &str) -> String {
+    match e {
+        Expr::Lit(lit) => intlit_str(lit, suffix),
+        Expr::Const(name, typ) => match suffix_type(suffix) {
+            Some(wide) if !matches!(typ, BaseType::U64 | BaseType::I64) => {
+                format!("({}){}", wide, name)
+            }
+            _ => name.clone(),
+        },
+        Expr::Neg(e) => neg_str(&expr_str(e, suffix)),
+        Expr::Paren(e) => format!("({})", expr_str(e, suffix)),
+        Expr::Binary(lhs, op, rhs) => format!(
+            "{} {} {}",
+            operand_str(lhs, *op, suffix),
+            op.as_str(),
+            // the type of a shift is that of its lhs, so the rhs needs no suffix
+            operand_str(rhs, *op, if *op == BinOp::Shl { "" } else { suffix })
+        ),
+    }
+}
+
+fn operand_str(e: &Expr, outer: BinOp, suffix: &str) -> String {
+    match e {
+        Expr::Binary(_, op, _) if op.needs_parens_within(outer) => {
+            format!("({})", expr_str(e, suffix))
         }
+        _ => expr_str(e, suffix),
     }
 }
 
-fn field_str(f: &Field) -> String {
-    let (front, back) = type_str(&f.typ);
-    format!("{} {}{}", front, f.name, back)
+// avoid emitting "--" which is the decrement operator in C
+fn neg_str(s: &str) -> String {
+    if s.starts_with('-') {
+        format!("-({})", s)
+    } else {
+        format!("-{}", s)
+    }
+}
+
+fn field_str(f: &Field, null_unspecified: bool) -> String {
+    decl_str(&f.typ, &f.name, null_unspecified)
 }
 
-fn ret_str(t: &ReturnType) -> String {
+// Declarator for a function "name(args)" returning the type
+fn ret_decl_str(t: &ReturnType, func: &str, null_unspecified: bool) -> String {
     match t {
-        ReturnType::None => "void".to_string(),
-        ReturnType::Some(t) => {
-            let (f, b) = type_str(t);
-            f + &b
+        ReturnType::None => format!("void {}", func),
+        ReturnType::Some(t) => decl_str(t, func, null_unspecified),
+    }
+}
+
+// GNU attributes for non-null args (numbered from 1) and return values
+fn fn_attrs_str(args: &[Field], ret: &ReturnType) -> String {
+    let mut attrs = vec![];
+    let nonnull: Vec<String> = (1..)
+        .zip(args)
+        .filter(|(_, f)| f.typ.nullability() == Some(Nullability::NonNull))
+        .map(|(i, _)| i.to_string())
+        .collect();
+    if !nonnull.is_empty() {
+        attrs.push(format!("nonnull({})", nonnull.join(", ")));
+    }
+    if let ReturnType::Some(t) = ret {
+        if t.nullability() == Some(Nullability::NonNull) {
+            attrs.push("returns_nonnull".to_string());
         }
     }
+    if attrs.is_empty() {
+        "".to_string()
+    } else {
+        format!("__attribute__(({})) ", attrs.join(", "))
+    }
 }
 
-fn args_str(args: &[Field]) -> String {
+fn args_str(args: &[Field], null_unspecified: bool) -> String {
     let mut s = String::new();
     for f in args {
         if !s.is_empty() {
             s += ", ";
         }
-        s += &field_str(f);
+        s += &field_str(f, null_unspecified);
     }
     // quirky C: empty args is (void)
     if s.is_empty() {
@@ -109,44 +372,412 @@ fn emit_skip(out: &mut dyn std::fmt::Write, skip: &Skip) -> std::fmt::Result {
             SkipElem::Whitespace(w) => write!(out, "{}", w)?,
             SkipElem::LineComment(txt) => write!(out, "//{}", txt)?,
             SkipElem::BlockComment(txt) => write!(out, "/*{}*/", txt)?,
+            // a doc comment with nothing to document is just a comment
+            SkipElem::DocComment(lines) => write!(out, "// {}", lines.join("\n// "))?,
         }
     }
     Ok(())
 }
 
-fn emit_fn(out: &mut dyn std::fmt::Write, decl: &FuncDecl) -> std::fmt::Result {
+// Doxygen comments: "/** doc */" or a "/** ... */" block for several lines
+fn emit_docs(out: &mut dyn std::fmt::Write, indent: &str, docs: &[String]) -> std::fmt::Result {
+    // the doc text must not end the comment early
+    let docs: Vec<String> = docs.iter().map(|l| l.replace("*/", "* /")).collect();
+    match docs.as_slice() {
+        [] => Ok(()),
+        [line] => writeln!(out, "{}/** {} */", indent, line),
+        lines => {
+            writeln!(out, "{}/**", indent)?;
+            for line in lines {
+                if line.is_empty() {
+                    writeln!(out, "{} *", indent)?;
+                } else {
+                    writeln!(out, "{} * {}", indent, line)?;
+                }
+            }
+            writeln!(out, "{} */", indent)
+        }
+    }
+}
+
+// Arg and return docs become "@param" and "@return" commands
+fn fn_docs(docs: &[String], args: &[Field], ret_docs: &[String]) -> Vec<String> {
+    let mut commands = vec![];
+    for arg in args
+        .iter()
+        .filter(|a| !a.docs.is_empty() || a.direction().is_some())
+    {
+        let param = match arg.direction() {
+            None => "@param",
+            Some(Direction::In) => "@param[in]",
+            Some(Direction::Out) => "@param[out]",
+            Some(Direction::InOut) => "@param[in,out]",
+        };
+        match arg.docs.split_first() {
+            Some((first, rest)) => {
+                commands.push(format!("{} {} {}", param, arg.name, first));
+                commands.extend(rest.iter().map(|l| format!("  {}", l)));
+            }
+            None => commands.push(format!("{} {}", param, arg.name)),
+        }
+    }
+    if let Some((first, rest)) = ret_docs.split_first() {
+        commands.push(format!("@return {}", first));
+        commands.extend(rest.iter().map(|l| format!("  {}", l)));
+    }
+
+    let mut all = docs.to_vec();
+    if !all.is_empty() && !commands.is_empty() {
+        all.push("".to_string());
+    }
+    all.extend(commands);
+    all
+}
+
+fn emit_fn(
+    out: &mut dyn std::fmt::Write,
+    decl: &FuncDecl,
+    export: &str,
+    null_unspecified: bool,
+) -> std::fmt::Result {
     emit_skip(out, &decl.prefix)?;
-    write!(
-        out,
-        "DANDIYA_API_EXPORT {} {}_v{}({});",
-        ret_str(&decl.ret),
+    emit_docs(out, "", &fn_docs(&decl.docs, &decl.args, &decl.ret_docs))?;
+    let func = format!(
+        "{}_v{}({})",
         decl.name,
         decl.version.0,
-        args_str(&decl.args)
+        args_str(&decl.args, null_unspecified)
+    );
+    write!(
+        out,
+        "{} {}{};",
+        export,
+        fn_attrs_str(&decl.args, &decl.ret),
+        ret_decl_str(&decl.ret, &func, null_unspecified)
     )
 }
 
-fn emit_struct(out: &mut dyn std::fmt::Write, decl: &StructDecl) -> std::fmt::Result {
+fn emit_struct(
+    out: &mut dyn std::fmt::Write,
+    decl: &StructDecl,
+    null_unspecified: bool,
+) -> std::fmt::Result {
     emit_skip(out, &decl.prefix)?;
-    write!(out, "typedef struct {} {}_t;\n", decl.name, decl.name)?;
-    write!(out, "struct {} {{\n", decl.name)?;
+    emit_docs(out, "", &decl.docs)?;
+    writeln!(out, "typedef struct {} {}_t;", decl.name, decl.name)?;
+    writeln!(out, "struct {} {{", decl.name)?;
+    for (f, name) in decl.fields.iter().zip(decl.field_names()) {
+        emit_docs(out, "  ", &f.docs)?;
+        writeln!(out, "  {};", decl_str(&f.typ, &name, null_unspecified))?;
+    }
+    if decl.packed() {
+        write!(out, "}} __attribute__((packed));")?;
+    } else if let Some(align) = decl.align() {
+        write!(out, "}} __attribute__((aligned({})));", align)?;
+    } else {
+        write!(out, "}};")?;
+    }
+    if decl.has_defaults() {
+        emit_struct_init(out, decl)?;
+    }
+    if decl.has_bits() {
+        emit_struct_bits(out, decl, null_unspecified)?;
+    }
+    Ok(())
+}
+
+// Accessors for the bit ranges: e.g. "example_desc_flags_kind()" and
+// "example_desc_set_flags_kind()"
+fn emit_struct_bits(
+    out: &mut dyn std::fmt::Write,
+    decl: &StructDecl,
+    null_unspecified: bool,
+) -> std::fmt::Result {
+    let strukt = BaseType::Struct(decl.name.clone());
+    let get = decl_str(
+        &inline_ptr(Mutability::Const, strukt.clone(), null_unspecified),
+        "s",
+        false,
+    );
+    let set = decl_str(
+        &inline_ptr(Mutability::Mut, strukt, null_unspecified),
+        "s",
+        false,
+    );
     for f in &decl.fields {
-        write!(out, "  {};\n", field_str(f))?;
+        let Some(bits) = &f.bits else {
+            continue;
+        };
+        let (typ, _) = type_str(&f.typ, false);
+        // unsigned literals, so that the promoted field is never shifted as an int
+        let suffix = match bits.repr {
+            BaseType::U64 => "ULL",
+            _ => "U",
+        };
+        for r in &bits.ranges {
+            let mask = format!("0x{:X}{}", r.mask(), suffix);
+            write!(
+                out,
+                "\nstatic inline {} {}_{}_{}({}) {{ return ({})((s->{} >> {}) & {}); }}",
+                typ, decl.name, f.name, r.name, get, typ, f.name, r.offset, mask
+            )?;
+            write!(
+                out,
+                "\nstatic inline void {}_set_{}_{}({}, {} v) {{ s->{} = ({})((s->{} & ~({} << {})) | ((v & {}) << {})); }}",
+                decl.name, f.name, r.name, set, typ, f.name, typ, f.name, mask, r.offset, mask, r.offset
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn init_macro(strukt: &str) -> String {
+    format!("{}_INIT", strukt.to_uppercase())
+}
+
+// The fields without a default are zeroed. The macro is C only: C++ only has
+// designated initializers from C++20, and warns about the fields left out of
+// them (even for "{0}"). So the function zeroes the struct and assigns the
+// defaults instead
+fn emit_struct_init(out: &mut dyn std::fmt::Write, decl: &StructDecl) -> std::fmt::Result {
+    let inits: Vec<String> = decl
+        .fields
+        .iter()
+        .filter_map(|f| {
+            let val = match f.default.as_ref()? {
+                FieldDefault::Int(typ, expr) => constexpr_str(expr, int_suffix(typ)),
+                FieldDefault::Struct(strukt) => init_macro(strukt),
+            };
+            Some(format!(".{} = {}", f.name, val))
+        })
+        .collect();
+    write!(out, "\n#ifndef __cplusplus")?;
+    write!(
+        out,
+        "\n#define {} {{ {} }}",
+        init_macro(&decl.name),
+        inits.join(", ")
+    )?;
+    write!(out, "\n#endif")?;
+    write!(
+        out,
+        "\nstatic inline {}_t {}_init(void) {{",
+        decl.name, decl.name
+    )?;
+    write!(out, "\n  {}_t s;", decl.name)?;
+    write!(out, "\n  __builtin_memset(&s, 0, sizeof s);")?;
+    for f in &decl.fields {
+        match &f.default {
+            Some(FieldDefault::Int(typ, expr)) => write!(
+                out,
+                "\n  s.{} = {};",
+                f.name,
+                constexpr_str(expr, int_suffix(typ))
+            )?,
+            Some(FieldDefault::Struct(strukt)) => {
+                write!(out, "\n  s.{} = {}_init();", f.name, strukt)?
+            }
+            None => {}
+        }
+    }
+    write!(out, "\n  return s;")?;
+    write!(out, "\n}}")
+}
+
+fn emit_union(
+    out: &mut dyn std::fmt::Write,
+    decl: &UnionDecl,
+    null_unspecified: bool,
+) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    writeln!(out, "typedef union {} {}_t;", decl.name, decl.name)?;
+    writeln!(out, "union {} {{", decl.name)?;
+    for f in &decl.fields {
+        emit_docs(out, "  ", &f.docs)?;
+        writeln!(out, "  {};", field_str(f, null_unspecified))?;
+    }
+    write!(out, "}};")
+}
+
+// Lowered to a struct of a fixed-width tag and a union of the payloads
+fn emit_variant(
+    out: &mut dyn std::fmt::Write,
+    decl: &VariantDecl,
+    null_unspecified: bool,
+) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    let tag = base_str(&decl.tag);
+    writeln!(out, "typedef struct {} {}_t;", decl.name, decl.name)?;
+    for (i, c) in decl.cases.iter().enumerate() {
+        writeln!(
+            out,
+            "#define {}_{} (({})({}))",
+            decl.name.to_uppercase(),
+            c.name.to_uppercase(),
+            tag,
+            i
+        )?;
+    }
+    writeln!(out, "struct {} {{", decl.name)?;
+    writeln!(out, "  {} tag;", tag)?;
+    if decl.has_payloads() {
+        writeln!(out, "  union {{")?;
+        for c in &decl.cases {
+            if let Some(typ) = &c.payload {
+                writeln!(out, "    {};", decl_str(typ, &c.name, null_unspecified))?;
+            }
+        }
+        writeln!(out, "  }} data;")?;
     }
     write!(out, "}};")
 }
 
+fn emit_callback(
+    out: &mut dyn std::fmt::Write,
+    decl: &CallbackDecl,
+    null_unspecified: bool,
+) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    let typ = &decl.typ;
+    emit_docs(out, "", &fn_docs(&decl.docs, &typ.args, &typ.ret_docs))?;
+    let name = format!("{}_t", decl.name);
+    let typ = fntype_str(&decl.typ, null_unspecified);
+    write!(out, "typedef {};", join_decl(typ, &name))
+}
+
+fn emit_alias(
+    out: &mut dyn std::fmt::Write,
+    decl: &AliasDecl,
+    null_unspecified: bool,
+) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    let name = format!("{}_t", decl.name);
+    write!(
+        out,
+        "typedef {};",
+        decl_str(&decl.typ, &name, null_unspecified)
+    )
+}
+
 fn emit_opaque(out: &mut dyn std::fmt::Write, decl: &OpaqueDecl) -> std::fmt::Result {
     emit_skip(out, &decl.prefix)?;
-    write!(out, "typedef struct {} {}_t;", decl.name, decl.name)
+    emit_docs(out, "", &decl.docs)?;
+    write!(out, "typedef struct {} {}_t;", decl.name, decl.name)?;
+    let Some(size) = &decl.size else {
+        return Ok(());
+    };
+    // storage only: the contents stay private to the library
+    writeln!(out)?;
+    writeln!(out, "struct {} {{", decl.name)?;
+    let size = constexpr_str(size, int_suffix(&BaseType::U64));
+    writeln!(out, "  unsigned char _data[{}];", size)?;
+    match decl.align {
+        Some(align) => write!(out, "}} __attribute__((aligned({})));", align),
+        None => write!(out, "}};"),
+    }
 }
 
 fn emit_const(out: &mut dyn std::fmt::Write, decl: &ConstDecl) -> std::fmt::Result {
     emit_skip(out, &decl.prefix)?;
-    write!(out, "#define {} ((uint64_t)({}))", decl.name, decl.val)
+    emit_docs(out, "", &decl.docs)?;
+    match &decl.val {
+        ConstValue::Int(typ, expr) => write!(
+            out,
+            "#define {} (({})({}))",
+            decl.name,
+            base_str(typ),
+            constexpr_str(expr, int_suffix(typ))
+        ),
+        ConstValue::Str(s) => write!(out, "#define {} \"{}\"", decl.name, s),
+    }
 }
 
-fn emit_apis(out: &mut dyn std::fmt::Write, apis: &Apis) -> std::fmt::Result {
+fn emit_enum(out: &mut dyn std::fmt::Write, decl: &EnumDecl) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    write!(out, "typedef {} {}_t;", base_str(&decl.repr), decl.name)?;
+    for e in &decl.enumerators {
+        write!(
+            out,
+            "\n#define {}_{} (({}_t)({}))",
+            decl.name.to_uppercase(),
+            e.name,
+            decl.name,
+            intlit_str(&e.val, "")
+        )?;
+    }
+    Ok(())
+}
+
+fn emit_flags(out: &mut dyn std::fmt::Write, decl: &FlagsDecl) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    write!(out, "typedef {} {}_t;", base_str(&decl.repr), decl.name)?;
+    for f in &decl.flags {
+        write!(
+            out,
+            "\n#define {}_{} (({}_t)({}))",
+            decl.name.to_uppercase(),
+            f.name,
+            decl.name,
+            f.val.text
+        )?;
+    }
+    Ok(())
+}
+
+fn emit_errors(
+    out: &mut dyn std::fmt::Write,
+    decl: &ErrorsDecl,
+    null_unspecified: bool,
+) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    write!(out, "typedef {} {}_t;", base_str(&decl.repr), decl.name)?;
+    for e in &decl.errors {
+        write!(
+            out,
+            "\n#define {}_{} (({}_t)({}))",
+            decl.name.to_uppercase(),
+            e.name,
+            decl.name,
+            intlit_str(&e.val, "")
+        )?;
+    }
+    writeln!(out)?;
+    let func = format!("{}({}_t err)", decl.strerror_name(), decl.name);
+    let msg = inline_ptr(Mutability::Const, BaseType::CChar, null_unspecified);
+    writeln!(out, "static inline {} {{", decl_str(&msg, &func, false))?;
+    writeln!(out, "  switch (err) {{")?;
+    for e in &decl.errors {
+        writeln!(
+            out,
+            "    case {}_{}: return \"{}\";",
+            decl.name.to_uppercase(),
+            e.name,
+            e.msg
+        )?;
+    }
+    writeln!(out, "    default: return \"unknown error\";")?;
+    writeln!(out, "  }}")?;
+    write!(out, "}}")
+}
+
+// The imported file's bindings are expected alongside: e.g. "types.h"
+fn emit_import(out: &mut dyn std::fmt::Write, decl: &ImportDecl) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    write!(out, "#include \"{}.h\"", decl.stem())
+}
+
+fn emit_apis(
+    out: &mut dyn std::fmt::Write,
+    apis: &Apis,
+    null_unspecified: bool,
+) -> std::fmt::Result {
     write!(out, "{}", API_HEADER)?;
     for api in &apis.apis {
         let decl = match api.latest() {
@@ -154,12 +785,13 @@ fn emit_apis(out: &mut dyn std::fmt::Write, apis: &Apis) -> std::fmt::Result {
             _ => panic!("expected fn decl"),
         };
 
-        write!(
+        let func = format!("{}({})", decl.name, args_str(&decl.args, null_unspecified));
+        emit_docs(out, "", &fn_docs(&decl.docs, &decl.args, &decl.ret_docs))?;
+        writeln!(
             out,
-            "static inline {} {}({}) {{ return {}_v{}({}); }}\n",
-            ret_str(&decl.ret),
-            decl.name,
-            args_str(&decl.args),
+            "static inline {}{} {{ return {}_v{}({}); }}",
+            fn_attrs_str(&decl.args, &decl.ret),
+            ret_decl_str(&decl.ret, &func, null_unspecified),
             decl.name,
             decl.version.0,
             call_str(&decl.args),
@@ -169,24 +801,40 @@ fn emit_apis(out: &mut dyn std::fmt::Write, apis: &Apis) -> std::fmt::Result {
 }
 
 pub fn emit(out: &mut dyn std::fmt::Write, defn: &ApiDefn, options: Options) -> std::fmt::Result {
-    write!(out, "{}", PREAMBLE)?;
+    write!(out, "{}", preamble(defn))?;
+    if let Some(lib) = &defn.library {
+        emit_skip(out, &lib.prefix)?;
+    }
+    if let Some(symbol_prefix) = &defn.symbol_prefix {
+        emit_skip(out, &symbol_prefix.prefix)?;
+    }
 
     // emit decls
+    let export = export_macro(defn.library.as_ref());
+    let null_unspecified = uses_nullability(defn);
     for decl in &defn.decls {
         match decl.as_ref() {
-            Decl::Fn(decl) => emit_fn(out, decl)?,
-            Decl::Struct(decl) => emit_struct(out, decl)?,
+            Decl::Fn(decl) => emit_fn(out, decl, &export, null_unspecified)?,
+            Decl::Struct(decl) => emit_struct(out, decl, null_unspecified)?,
+            Decl::Union(decl) => emit_union(out, decl, null_unspecified)?,
+            Decl::Variant(decl) => emit_variant(out, decl, null_unspecified)?,
+            Decl::Callback(decl) => emit_callback(out, decl, null_unspecified)?,
+            Decl::Alias(decl) => emit_alias(out, decl, null_unspecified)?,
             Decl::Opaque(decl) => emit_opaque(out, decl)?,
             Decl::Const(decl) => emit_const(out, decl)?,
+            Decl::Enum(decl) => emit_enum(out, decl)?,
+            Decl::Flags(decl) => emit_flags(out, decl)?,
+            Decl::Errors(decl) => emit_errors(out, decl, null_unspecified)?,
+            Decl::Import(decl) => emit_import(out, decl)?,
         }
     }
     emit_skip(out, &defn.suffix)?;
 
     // emit api forwarding
     if options.api_forward_to_latest {
-        emit_apis(out, &defn.apis)?;
+        emit_apis(out, &defn.apis, null_unspecified)?;
     }
 
-    write!(out, "{}", POSTAMBLE)?;
+    write!(out, "{}", postamble(defn))?;
     Ok(())
 }
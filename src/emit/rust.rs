@@ -1,40 +1,174 @@
 use crate::ast::*;
 use crate::emit::Options;
+use std::collections::HashMap;
+use std::rc::Rc;
 
-pub const PREAMBLE: &str = "\
+pub const POSTAMBLE: &str = "";
+
+pub const API_HEADER: &str = "
 /*******************************************************************************
- * Autogenerated by Dandiya API Generator
+ * API Inlines
  ******************************************************************************/
-#![allow(dead_code)]
-
 ";
 
-pub const POSTAMBLE: &str = "";
+pub const HANDLES_HEADER: &str = "
+/*******************************************************************************
+ * Owned Handles
+ ******************************************************************************/
+";
 
-pub const API_HEADER: &str = "
+pub const UNPREFIXED_HEADER: &str = "
 /*******************************************************************************
- * API Inlines
+ * Unprefixed Names
  ******************************************************************************/
 ";
 
+// The start of the module: a library also names itself in the banner, links
+// itself if it has a link name, and declares its version and soname
+pub fn preamble(defn: &ApiDefn) -> String {
+    let mut preamble = crate::emit::banner(defn.library.as_ref());
+    preamble += "#![allow(dead_code)]\n\n";
+    if let Some(lib) = &defn.library {
+        preamble += &library_items(lib);
+    }
+    preamble
+}
+
+fn library_items(lib: &Library) -> String {
+    let prefix = lib.macro_prefix();
+    let mut items = String::new();
+    if let Some(link) = &lib.link {
+        items += &format!("#[link(name = \"{}\")]\nextern \"C\" {{}}\n", link);
+    }
+    if let Some(version) = &lib.version {
+        items += &format!("pub const {}_VERSION: &str = \"{}\";\n", prefix, version);
+    }
+    if let Some(soname) = &lib.soname {
+        items += &format!("pub const {}_SONAME: &str = \"{}\";\n", prefix, soname);
+    }
+    if !items.is_empty() {
+        items += "\n";
+    }
+    items
+}
+
 fn type_str(t: &Type) -> String {
     match t {
-        Type::Pointer(subtype) => format!("*mut {}", type_str(subtype)),
+        Type::Pointer(Mutability::Mut, _, subtype) => format!("*mut {}", type_str(subtype)),
+        Type::Pointer(Mutability::Const, _, subtype) => format!("*const {}", type_str(subtype)),
         Type::Array(subtype, len) => {
-            format!("[{}; {}]", type_str(subtype), len)
+            format!("[{}; {}]", type_str(subtype), constexpr_str(len, "usize"))
         }
-        Type::Base(base) => match base {
-            BaseType::Struct(s) => s,
-            BaseType::U8 => "u8",
-            BaseType::I8 => "i8",
-            BaseType::U16 => "u16",
-            BaseType::I16 => "i16",
-            BaseType::U32 => "u32",
-            BaseType::I32 => "i32",
-            BaseType::U64 => "u64",
-            BaseType::I64 => "i64",
+        Type::FnPtr(fntype) => fntype_str(fntype),
+        Type::Base(base) => base_str(base),
+    }
+}
+
+// Function pointers are nullable in C, so wrap in an Option
+fn fntype_str(fntype: &FnType) -> String {
+    format!(
+        "Option<unsafe extern \"C\" fn({}){}>",
+        args_str(&fntype.args),
+        ret_str(&fntype.ret)
+    )
+}
+
+fn base_str(base: &BaseType) -> String {
+    match base {
+        BaseType::Struct(s) => s,
+        BaseType::U8 => "u8",
+        BaseType::I8 => "i8",
+        BaseType::U16 => "u16",
+        BaseType::I16 => "i16",
+        BaseType::U32 => "u32",
+        BaseType::I32 => "i32",
+        BaseType::U64 => "u64",
+        BaseType::I64 => "i64",
+        BaseType::Bool => "bool",
+        BaseType::F32 => "f32",
+        BaseType::F64 => "f64",
+        BaseType::USize => "usize",
+        BaseType::ISize => "isize",
+        BaseType::CChar => "core::ffi::c_char",
+        BaseType::CSChar => "core::ffi::c_schar",
+        BaseType::CUChar => "core::ffi::c_uchar",
+        BaseType::CShort => "core::ffi::c_short",
+        BaseType::CUShort => "core::ffi::c_ushort",
+        BaseType::CInt => "core::ffi::c_int",
+        BaseType::CUInt => "core::ffi::c_uint",
+        BaseType::CLong => "core::ffi::c_long",
+        BaseType::CULong => "core::ffi::c_ulong",
+        BaseType::CLongLong => "core::ffi::c_longlong",
+        BaseType::CULongLong => "core::ffi::c_ulonglong",
+    }
+    .to_string()
+}
+
+// References to constants of a different type are cast to the target type
+fn constexpr_str(e: &ConstExpr, target: &str) -> String {
+    match &e.expr {
+        Expr::Const(name, typ) if base_str(typ) != target => format!("{} as {}", name, target),
+        expr => expr_str(expr, target),
+    }
+}
+
+fn expr_str(e: &Expr, target: &str) -> String {
+    match e {
+        Expr::Lit(lit) => lit.text.clone(),
+        Expr::Const(name, typ) if base_str(typ) == target => name.clone(),
+        Expr::Const(name, _) => format!("({} as {})", name, target),
+        Expr::Neg(e) => neg_str(&expr_str(e, target)),
+        Expr::Paren(e) => format!("({})", expr_str(e, target)),
+        Expr::Binary(lhs, op, rhs) => format!(
+            "{} {} {}",
+            operand_str(lhs, *op, target),
+            op.as_str(),
+            operand_str(rhs, *op, target)
+        ),
+    }
+}
+
+// avoid emitting "--", which rustc warns is a double negation
+fn neg_str(s: &str) -> String {
+    if s.starts_with('-') {
+        format!("-({})", s)
+    } else {
+        format!("-{}", s)
+    }
+}
+
+fn operand_str(e: &Expr, outer: BinOp, target: &str) -> String {
+    match e {
+        Expr::Binary(_, op, _) if op.needs_parens_within(outer) => {
+            format!("({})", expr_str(e, target))
         }
-        .to_string(),
+        _ => expr_str(e, target),
+    }
+}
+
+// Rust union fields must not need dropping, so anything that isn't trivially
+// Copy gets wrapped in ManuallyDrop (which is repr(transparent))
+fn union_field_type_str(t: &Type, symbols: &HashMap<String, Rc<Decl>>) -> String {
+    if type_is_trivially_copy(t, symbols) {
+        type_str(t)
+    } else {
+        format!("core::mem::ManuallyDrop<{}>", type_str(t))
+    }
+}
+
+// Named types are Copy unless they are structs, unions or variants (or opaque):
+// enums, flags and errors are Copy newtypes, and callbacks are fn pointers
+fn type_is_trivially_copy(t: &Type, symbols: &HashMap<String, Rc<Decl>>) -> bool {
+    match t {
+        Type::Pointer(_, _, _) => true,
+        Type::FnPtr(_) => true,
+        Type::Array(subtype, _) => type_is_trivially_copy(subtype, symbols),
+        Type::Base(BaseType::Struct(name)) => match symbols.get(name).map(Rc::as_ref) {
+            Some(Decl::Enum(_) | Decl::Flags(_) | Decl::Errors(_) | Decl::Callback(_)) => true,
+            Some(Decl::Alias(decl)) => type_is_trivially_copy(&decl.typ, symbols),
+            _ => false,
+        },
+        Type::Base(_) => true,
     }
 }
 
@@ -56,59 +190,652 @@ fn args_str(args: &[Field]) -> String {
     s
 }
 
+// The API wrappers state nullability in the type of top-level pointers. The
+// raw declarations keep raw pointers: they must match the C signatures exactly
+fn wrapper_type_str(t: &Type) -> String {
+    match t {
+        Type::Pointer(_, Nullability::NonNull, subtype) => {
+            format!("core::ptr::NonNull<{}>", type_str(subtype))
+        }
+        Type::Pointer(_, Nullability::Nullable, subtype) => {
+            format!("Option<core::ptr::NonNull<{}>>", type_str(subtype))
+        }
+        _ => type_str(t),
+    }
+}
+
+// The API wrappers return the return value (if any), and then any out args
+fn wrapper_ret_str(decl: &FuncDecl) -> String {
+    let mut types = vec![];
+    match (&decl.ret, decl.returns_status(), decl.constructs()) {
+        (_, _, Some(constructed)) => types.push(format!("Option<{}>", handle_str(constructed))),
+        (ReturnType::Some(t), None, None) => types.push(wrapper_type_str(t)),
+        _ => (),
+    }
+    types.extend(
+        returned_args(&decl.args)
+            .iter()
+            .map(|a| pointee_str(&a.typ)),
+    );
+    let ret = match types.len() {
+        0 => "()".to_string(),
+        1 => types.remove(0),
+        _ => format!("({})", types.join(", ")),
+    };
+    match decl.returns_status() {
+        Some(status) => format!(" -> Result<{}, {}>", ret, status),
+        None if ret == "()" => "".to_string(),
+        None => format!(" -> {}", ret),
+    }
+}
+
+fn pointee_str(t: &Type) -> String {
+    match t {
+        Type::Pointer(_, _, subtype) => type_str(subtype),
+        _ => type_str(t),
+    }
+}
+
+// Out args are returned by the API wrappers, except for slices which are
+// written in place
+fn returned_args(args: &[Field]) -> Vec<&Field> {
+    args.iter()
+        .filter(|a| a.direction() == Some(Direction::Out) && len_arg(args, a).is_none())
+        .collect()
+}
+
+// Inout args are taken by reference by the API wrappers
+fn inout_type_str(t: &Type) -> String {
+    match t {
+        Type::Pointer(_, Nullability::Nullable, _) => format!("Option<&mut {}>", pointee_str(t)),
+        _ => format!("&mut {}", pointee_str(t)),
+    }
+}
+
+// Out args start zeroed: all types in the bindings are valid when zeroed, so
+// nothing is undefined if the callee doesn't write them (e.g. on an error)
+fn out_lets_str(args: &[Field]) -> String {
+    let mut s = String::new();
+    for f in returned_args(args) {
+        s += &format!(
+            "let mut {}: {} = core::mem::zeroed(); ",
+            f.name,
+            pointee_str(&f.typ)
+        );
+    }
+    s
+}
+
+// Returns the out args along with the result of the call
+fn wrapper_body_str(decl: &FuncDecl, call: String) -> String {
+    let outs: Vec<&str> = returned_args(&decl.args)
+        .iter()
+        .map(|a| a.name.as_str())
+        .collect();
+    // Statuses are matched against 0 (success): the other codes are errors
+    if let Some(status) = decl.returns_status() {
+        let call = match &decl.ret {
+            ReturnType::Some(Type::Base(BaseType::Struct(s))) if s == status => call,
+            _ => format!("{}({})", status, call),
+        };
+        let ok = match outs.len() {
+            1 => outs[0].to_string(),
+            _ => format!("({})", outs.join(", ")),
+        };
+        return format!(
+            "match {} {{ {}(0) => Ok({}), err => Err(err) }}",
+            call, status, ok
+        );
+    }
+    let call = match decl.constructs() {
+        Some(constructed) => format!("{}::from_raw({})", handle_str(constructed), call),
+        None => wrap_ret_str(&decl.ret, call),
+    };
+    match (&decl.ret, outs.len()) {
+        (_, 0) => call,
+        (ReturnType::None, 1) => format!("{}; {}", call, outs[0]),
+        (ReturnType::None, _) => format!("{}; ({})", call, outs.join(", ")),
+        (ReturnType::Some(_), _) => format!("({}, {})", call, outs.join(", ")),
+    }
+}
+
+// The arg that is the length of a pointer arg: "#[len_of(ptr)]"
+fn len_arg<'a>(args: &'a [Field], ptr: &Field) -> Option<&'a Field> {
+    args.iter().find(|a| a.len_of() == Some(ptr.name.as_str()))
+}
+
+// Pointers with a length arg are taken as slices by the API wrappers
+fn slice_type_str(t: &Type) -> String {
+    let Type::Pointer(mutability, nullability, subtype) = t else {
+        return type_str(t);
+    };
+    let slice = match mutability {
+        Mutability::Const => format!("&[{}]", type_str(subtype)),
+        Mutability::Mut => format!("&mut [{}]", type_str(subtype)),
+    };
+    match nullability {
+        Nullability::Nullable => format!("Option<{}>", slice),
+        _ => slice,
+    }
+}
+
+fn slice_ptr_str(name: &str, t: &Type) -> String {
+    match t {
+        Type::Pointer(Mutability::Const, Nullability::Nullable, _) => {
+            format!("{}.map_or(core::ptr::null(), |s| s.as_ptr())", name)
+        }
+        Type::Pointer(Mutability::Mut, Nullability::Nullable, _) => {
+            format!("{}.map_or(core::ptr::null_mut(), |s| s.as_mut_ptr())", name)
+        }
+        Type::Pointer(Mutability::Const, _, _) => format!("{}.as_ptr()", name),
+        _ => format!("{}.as_mut_ptr()", name),
+    }
+}
+
+// Length args aren't taken by the API wrappers: they are bound from the length
+// of their slices before the call
+fn len_lets_str(args: &[Field]) -> String {
+    let mut s = String::new();
+    for f in args {
+        let Some(ptr) = args.iter().find(|a| Some(a.name.as_str()) == f.len_of()) else {
+            continue;
+        };
+        let len = match ptr.typ.nullability() {
+            Some(Nullability::Nullable) => {
+                format!("{}.as_deref().map_or(0, |s| s.len())", ptr.name)
+            }
+            _ => format!("{}.len()", ptr.name),
+        };
+        let typ = type_str(&f.typ);
+        if typ == "usize" {
+            s += &format!("let {} = {}; ", f.name, len);
+        } else {
+            s += &format!("let {} = {} as {}; ", f.name, len, typ);
+        }
+    }
+    s
+}
+
+fn wrapper_args_str(args: &[Field]) -> String {
+    let mut s = String::new();
+    let returned = returned_args(args);
+    for f in args
+        .iter()
+        .filter(|f| f.len_of().is_none() && !returned.contains(f))
+    {
+        if !s.is_empty() {
+            s += ", ";
+        }
+        let typ = match (len_arg(args, f), f.direction()) {
+            (Some(_), _) => slice_type_str(&f.typ),
+            (None, Some(Direction::InOut)) => inout_type_str(&f.typ),
+            (None, _) => wrapper_type_str(&f.typ),
+        };
+        s += &format!("{}: {}", f.name, typ);
+    }
+    s
+}
+
+// Converts the wrapper args back into raw pointers
 fn call_str(args: &[Field]) -> String {
+    let returned = returned_args(args);
     let mut s = String::new();
     for f in args {
         if !s.is_empty() {
             s += ", ";
         }
-        s += &f.name;
+        s += &match f.typ.nullability() {
+            _ if len_arg(args, f).is_some() => slice_ptr_str(&f.name, &f.typ),
+            _ if returned.contains(&f) => format!("&mut {}", f.name),
+            Some(Nullability::Nullable) if f.direction() == Some(Direction::InOut) => {
+                format!("{}.map_or(core::ptr::null_mut(), |p| p)", f.name)
+            }
+            _ if f.direction() == Some(Direction::InOut) => f.name.clone(),
+            Some(Nullability::NonNull) => format!("{}.as_ptr()", f.name),
+            Some(Nullability::Nullable) => {
+                format!("{}.map_or(core::ptr::null_mut(), |p| p.as_ptr())", f.name)
+            }
+            _ => f.name.clone(),
+        };
     }
     s
 }
 
+// Converts the raw return value of a call into the wrapper return type
+fn wrap_ret_str(t: &ReturnType, call: String) -> String {
+    let ReturnType::Some(t) = t else {
+        return call;
+    };
+    let raw = match t {
+        Type::Pointer(Mutability::Const, _, _) => format!("{} as *mut _", call),
+        _ => call.clone(),
+    };
+    match t.nullability() {
+        Some(Nullability::NonNull) => format!("core::ptr::NonNull::new_unchecked({})", raw),
+        Some(Nullability::Nullable) => format!("core::ptr::NonNull::new({})", raw),
+        _ => call,
+    }
+}
+
 fn emit_skip(out: &mut dyn std::fmt::Write, skip: &Skip) -> std::fmt::Result {
     for s in &skip.0 {
         match s {
             SkipElem::Whitespace(w) => write!(out, "{}", w)?,
             SkipElem::LineComment(txt) => write!(out, "//{}", txt)?,
             SkipElem::BlockComment(txt) => write!(out, "/*{}*/", txt)?,
+            // a doc comment with nothing to document is just a comment
+            SkipElem::DocComment(lines) => write!(out, "// {}", lines.join("\n// "))?,
         }
     }
     Ok(())
 }
 
+fn emit_docs(out: &mut dyn std::fmt::Write, indent: &str, docs: &[String]) -> std::fmt::Result {
+    for line in docs {
+        if line.is_empty() {
+            writeln!(out, "{}///", indent)?;
+        } else {
+            writeln!(out, "{}/// {}", indent, line)?;
+        }
+    }
+    Ok(())
+}
+
+// rustdoc doesn't document fn args, so arg and return docs become sections
+fn fn_docs<'a>(
+    docs: &[String],
+    args: impl IntoIterator<Item = &'a Field>,
+    ret_docs: &[String],
+) -> Vec<String> {
+    let mut all = docs.to_vec();
+    let mut section = |title: &str, lines: Vec<String>| {
+        if !all.is_empty() {
+            all.push("".to_string());
+        }
+        all.push(format!("# {}", title));
+        all.push("".to_string());
+        all.extend(lines);
+    };
+    let documented: Vec<&Field> = args.into_iter().filter(|a| !a.docs.is_empty()).collect();
+    if !documented.is_empty() {
+        let mut lines = vec![];
+        for arg in documented {
+            lines.push(format!("* `{}` - {}", arg.name, arg.docs[0]));
+            lines.extend(arg.docs[1..].iter().map(|l| format!("  {}", l)));
+        }
+        section("Arguments", lines);
+    }
+    if !ret_docs.is_empty() {
+        section("Returns", ret_docs.to_vec());
+    }
+    all
+}
+
 fn emit_fn(out: &mut dyn std::fmt::Write, decl: &FuncDecl) -> std::fmt::Result {
     emit_skip(out, &decl.prefix)?;
-    write!(
-        out,
-        "extern \"C\" {{ pub fn {}_v{}({}){}; }}",
+    let func = format!(
+        "pub fn {}_v{}({}){};",
         decl.name,
         decl.version.0,
         args_str(&decl.args),
         ret_str(&decl.ret)
-    )
+    );
+    let docs = fn_docs(&decl.docs, &decl.args, &decl.ret_docs);
+    if docs.is_empty() {
+        return write!(out, "extern \"C\" {{ {} }}", func);
+    }
+    // rustdoc ignores docs on the extern block itself
+    writeln!(out, "extern \"C\" {{")?;
+    emit_docs(out, "  ", &docs)?;
+    writeln!(out, "  {}", func)?;
+    write!(out, "}}")
 }
 
 fn emit_struct(out: &mut dyn std::fmt::Write, decl: &StructDecl) -> std::fmt::Result {
     emit_skip(out, &decl.prefix)?;
-    write!(out, "#[repr(C)]\n")?;
-    write!(out, "pub struct {} {{\n", decl.name)?;
+    emit_docs(out, "", &decl.docs)?;
+    if decl.packed() {
+        writeln!(out, "#[repr(C, packed)]")?;
+    } else if let Some(align) = decl.align() {
+        writeln!(out, "#[repr(C, align({}))]", align)?;
+    } else {
+        writeln!(out, "#[repr(C)]")?;
+    }
+    writeln!(out, "pub struct {} {{", decl.name)?;
+    for (f, name) in decl.fields.iter().zip(decl.field_names()) {
+        emit_docs(out, "  ", &f.docs)?;
+        writeln!(out, "  pub {}: {},", name, type_str(&f.typ))?;
+    }
+    write!(out, "}}")?;
+    if decl.has_defaults() {
+        emit_struct_default(out, decl)?;
+    }
+    if decl.has_bits() {
+        emit_struct_bits(out, decl)?;
+    }
+    Ok(())
+}
+
+// Accessors for the bit ranges: e.g. "flags_kind()" and "set_flags_kind()"
+fn emit_struct_bits(out: &mut dyn std::fmt::Write, decl: &StructDecl) -> std::fmt::Result {
+    writeln!(out)?;
+    writeln!(out, "impl {} {{", decl.name)?;
     for f in &decl.fields {
-        write!(out, "  pub {}: {},\n", f.name, type_str(&f.typ))?;
+        let Some(bits) = &f.bits else {
+            continue;
+        };
+        let typ = type_str(&f.typ);
+        for r in &bits.ranges {
+            let mask = format!("0x{:X}", r.mask());
+            writeln!(
+                out,
+                "  pub fn {}_{}(&self) -> {} {{ (self.{} >> {}) & {} }}",
+                f.name, r.name, typ, f.name, r.offset, mask
+            )?;
+            writeln!(
+                out,
+                "  pub fn set_{}_{}(&mut self, v: {}) {{ self.{} = (self.{} & !({} << {})) | ((v & {}) << {}); }}",
+                f.name, r.name, typ, f.name, f.name, mask, r.offset, mask, r.offset
+            )?;
+        }
     }
     write!(out, "}}")
 }
 
+// Starts zeroed, like the C initializer: all types in the bindings are valid
+// when zeroed, which "#[derive(Default)]" can't do for large arrays
+fn emit_struct_default(out: &mut dyn std::fmt::Write, decl: &StructDecl) -> std::fmt::Result {
+    writeln!(out)?;
+    writeln!(out, "impl Default for {} {{", decl.name)?;
+    writeln!(out, "  fn default() -> Self {{")?;
+    writeln!(
+        out,
+        "    let mut s: Self = unsafe {{ core::mem::zeroed() }};"
+    )?;
+    for f in &decl.fields {
+        let val = match &f.default {
+            Some(FieldDefault::Int(typ, expr)) => constexpr_str(expr, &base_str(typ)),
+            Some(FieldDefault::Struct(_)) => "Default::default()".to_string(),
+            None => continue,
+        };
+        writeln!(out, "    s.{} = {};", f.name, val)?;
+    }
+    writeln!(out, "    s")?;
+    writeln!(out, "  }}")?;
+    write!(out, "}}")
+}
+
+fn emit_union(
+    out: &mut dyn std::fmt::Write,
+    decl: &UnionDecl,
+    symbols: &HashMap<String, Rc<Decl>>,
+) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    writeln!(out, "#[repr(C)]")?;
+    writeln!(out, "pub union {} {{", decl.name)?;
+    for f in &decl.fields {
+        emit_docs(out, "  ", &f.docs)?;
+        writeln!(
+            out,
+            "  pub {}: {},",
+            f.name,
+            union_field_type_str(&f.typ, symbols)
+        )?;
+    }
+    write!(out, "}}")
+}
+
+// Emits the raw C layout (tag + union of payloads) and a safe rust enum that
+// can be converted from the raw form, rejecting unknown tags
+fn emit_variant(
+    out: &mut dyn std::fmt::Write,
+    decl: &VariantDecl,
+    symbols: &HashMap<String, Rc<Decl>>,
+) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    let name = &decl.name;
+    let tag = base_str(&decl.tag);
+
+    // raw form
+    if decl.has_payloads() {
+        writeln!(out, "#[repr(C)]")?;
+        writeln!(out, "#[allow(non_snake_case)]")?;
+        writeln!(out, "pub union {}_data {{", name)?;
+        for c in &decl.cases {
+            if let Some(typ) = &c.payload {
+                writeln!(
+                    out,
+                    "  pub {}: {},",
+                    c.name,
+                    union_field_type_str(typ, symbols)
+                )?;
+            }
+        }
+        writeln!(out, "}}")?;
+    }
+    emit_docs(out, "", &decl.docs)?;
+    writeln!(out, "#[repr(C)]")?;
+    writeln!(out, "pub struct {} {{", name)?;
+    writeln!(out, "  pub tag: {},", tag)?;
+    if decl.has_payloads() {
+        writeln!(out, "  pub data: {}_data,", name)?;
+    }
+    writeln!(out, "}}")?;
+    writeln!(out, "impl {} {{", name)?;
+    for (i, c) in decl.cases.iter().enumerate() {
+        writeln!(
+            out,
+            "  pub const {}: {} = {};",
+            c.name.to_uppercase(),
+            tag,
+            i
+        )?;
+    }
+    writeln!(out, "}}")?;
+
+    // safe form
+    emit_docs(out, "", &decl.docs)?;
+    writeln!(out, "pub enum {}_enum {{", name)?;
+    for c in &decl.cases {
+        match &c.payload {
+            Some(typ) => writeln!(out, "  {}({}),", c.name, type_str(typ))?,
+            None => writeln!(out, "  {},", c.name)?,
+        }
+    }
+    writeln!(out, "}}")?;
+    writeln!(
+        out,
+        "impl core::convert::TryFrom<{}> for {}_enum {{",
+        name, name
+    )?;
+    writeln!(out, "  type Error = {};", tag)?;
+    writeln!(
+        out,
+        "  fn try_from(raw: {}) -> core::result::Result<{}_enum, {}> {{",
+        name, name, tag
+    )?;
+    writeln!(out, "    match raw.tag {{")?;
+    for c in &decl.cases {
+        let val = match &c.payload {
+            Some(typ) if type_is_trivially_copy(typ, symbols) => {
+                format!("(unsafe {{ raw.data.{} }})", c.name)
+            }
+            Some(_) => format!(
+                "(core::mem::ManuallyDrop::into_inner(unsafe {{ raw.data.{} }}))",
+                c.name
+            ),
+            None => "".to_string(),
+        };
+        writeln!(
+            out,
+            "      {}::{} => Ok({}_enum::{}{}),",
+            name,
+            c.name.to_uppercase(),
+            name,
+            c.name,
+            val
+        )?;
+    }
+    writeln!(out, "      tag => Err(tag),")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "  }}")?;
+    write!(out, "}}")
+}
+
+fn emit_callback(out: &mut dyn std::fmt::Write, decl: &CallbackDecl) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    let typ = &decl.typ;
+    emit_docs(out, "", &fn_docs(&decl.docs, &typ.args, &typ.ret_docs))?;
+    write!(out, "pub type {} = {};", decl.name, fntype_str(typ))
+}
+
+fn emit_alias(out: &mut dyn std::fmt::Write, decl: &AliasDecl) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    write!(out, "pub type {} = {};", decl.name, type_str(&decl.typ))
+}
+
 fn emit_opaque(out: &mut dyn std::fmt::Write, decl: &OpaqueDecl) -> std::fmt::Result {
     emit_skip(out, &decl.prefix)?;
-    write!(out, "#[repr(C)]\n")?;
-    write!(out, "pub struct {} {{_opaque_data: [u8; 0]}}", decl.name)
+    emit_docs(out, "", &decl.docs)?;
+    let Some(size) = &decl.size else {
+        writeln!(out, "#[repr(C)]")?;
+        return write!(out, "pub struct {} {{_opaque_data: [u8; 0]}}", decl.name);
+    };
+    match decl.align {
+        Some(align) => writeln!(out, "#[repr(C, align({}))]", align)?,
+        None => writeln!(out, "#[repr(C)]")?,
+    }
+    write!(
+        out,
+        "pub struct {} {{_data: [u8; {}]}}",
+        decl.name,
+        constexpr_str(size, "usize")
+    )
 }
 
 fn emit_const(out: &mut dyn std::fmt::Write, decl: &ConstDecl) -> std::fmt::Result {
     emit_skip(out, &decl.prefix)?;
-    write!(out, "pub const {}: u64 = {};", decl.name, decl.val)
+    emit_docs(out, "", &decl.docs)?;
+    match &decl.val {
+        ConstValue::Int(typ, expr) => write!(
+            out,
+            "pub const {}: {} = {};",
+            decl.name,
+            base_str(typ),
+            constexpr_str(expr, &base_str(typ))
+        ),
+        ConstValue::Str(s) => write!(out, "pub const {}: &str = \"{}\";", decl.name, s),
+    }
+}
+
+fn emit_enum(out: &mut dyn std::fmt::Write, decl: &EnumDecl) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    // A newtype rather than a rust enum: a newer library may hand back values
+    // that this definition doesn't know about, which would be UB for an enum
+    writeln!(out, "#[repr(transparent)]")?;
+    writeln!(out, "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]")?;
+    writeln!(
+        out,
+        "pub struct {}(pub {});",
+        decl.name,
+        base_str(&decl.repr)
+    )?;
+    writeln!(out, "impl {} {{", decl.name)?;
+    for e in &decl.enumerators {
+        writeln!(
+            out,
+            "  pub const {}: {} = {}({});",
+            e.name, decl.name, decl.name, e.val.text
+        )?;
+    }
+    write!(out, "}}")
+}
+
+fn emit_errors(out: &mut dyn std::fmt::Write, decl: &ErrorsDecl) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    let name = &decl.name;
+    // A newtype for the same reason as enums: unknown statuses are not UB
+    writeln!(out, "#[repr(transparent)]")?;
+    writeln!(out, "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]")?;
+    writeln!(out, "pub struct {}(pub {});", name, base_str(&decl.repr))?;
+    writeln!(out, "impl {} {{", name)?;
+    for e in &decl.errors {
+        writeln!(
+            out,
+            "  pub const {}: {} = {}({});",
+            e.name, name, name, e.val.text
+        )?;
+    }
+    writeln!(out, "  pub fn message(&self) -> &'static str {{")?;
+    writeln!(out, "    match *self {{")?;
+    for e in &decl.errors {
+        writeln!(out, "      {}::{} => \"{}\",", name, e.name, e.msg)?;
+    }
+    writeln!(out, "      _ => \"unknown error\",")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "  }}")?;
+    writeln!(out, "}}")?;
+    writeln!(out, "impl core::fmt::Display for {} {{", name)?;
+    writeln!(
+        out,
+        "  fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {{ f.write_str(self.message()) }}"
+    )?;
+    writeln!(out, "}}")?;
+    write!(out, "impl std::error::Error for {} {{}}", name)
+}
+
+fn emit_flags(out: &mut dyn std::fmt::Write, decl: &FlagsDecl) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    let name = &decl.name;
+    let repr = base_str(&decl.repr);
+    writeln!(out, "#[repr(transparent)]")?;
+    writeln!(
+        out,
+        "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]"
+    )?;
+    writeln!(out, "pub struct {}(pub {});", name, repr)?;
+    writeln!(out, "impl {} {{", name)?;
+    for f in &decl.flags {
+        writeln!(
+            out,
+            "  pub const {}: {} = {}({});",
+            f.name, name, name, f.val.text
+        )?;
+    }
+    writeln!(out, "  pub const fn empty() -> {} {{ {}(0) }}", name, name)?;
+    writeln!(out, "  pub const fn bits(self) -> {} {{ self.0 }}", repr)?;
+    writeln!(
+        out,
+        "  pub const fn contains(self, other: {}) -> bool {{ self.0 & other.0 == other.0 }}",
+        name
+    )?;
+    writeln!(out, "}}")?;
+    for (tr, method, op) in [("BitOr", "bitor", "|"), ("BitAnd", "bitand", "&")] {
+        writeln!(out, "impl core::ops::{} for {} {{", tr, name)?;
+        writeln!(out, "  type Output = {};", name)?;
+        writeln!(
+            out,
+            "  fn {}(self, rhs: {}) -> {} {{ {}(self.0 {} rhs.0) }}",
+            method, name, name, name, op
+        )?;
+        write!(out, "}}")?;
+        if op == "|" {
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+// The imported file's bindings are expected in a sibling module
+fn emit_import(out: &mut dyn std::fmt::Write, decl: &ImportDecl) -> std::fmt::Result {
+    emit_skip(out, &decl.prefix)?;
+    emit_docs(out, "", &decl.docs)?;
+    write!(out, "use super::{}::*;", decl.stem())
 }
 
 fn emit_apis(out: &mut dyn std::fmt::Write, apis: &Apis) -> std::fmt::Result {
@@ -119,37 +846,249 @@ fn emit_apis(out: &mut dyn std::fmt::Write, apis: &Apis) -> std::fmt::Result {
             _ => panic!("expected fn decl"),
         };
 
-        write!(
-            out,
-            "pub unsafe fn {}({}){} {{ {}_v{}({}) }}\n",
-            decl.name,
-            args_str(&decl.args),
-            ret_str(&decl.ret),
+        let returned = returned_args(&decl.args);
+        let args = decl
+            .args
+            .iter()
+            .filter(|a| a.len_of().is_none() && !returned.contains(a));
+        emit_docs(out, "", &fn_docs(&decl.docs, args, &decl.ret_docs))?;
+        let call = format!(
+            "{}_v{}({})",
             decl.name,
             decl.version.0,
-            call_str(&decl.args),
+            call_str(&decl.args)
+        );
+        writeln!(
+            out,
+            "pub unsafe fn {}({}){} {{ {}{}{} }}",
+            decl.name,
+            wrapper_args_str(&decl.args),
+            wrapper_ret_str(decl),
+            len_lets_str(&decl.args),
+            out_lets_str(&decl.args),
+            wrapper_body_str(decl, call),
         )?;
     }
     Ok(())
 }
 
+// The owning handle of an opaque type with a destructor: e.g. "example_ctx_handle"
+fn handle_str(opaque: &str) -> String {
+    format!("{}_handle", opaque)
+}
+
+// An owning handle: released when dropped, and retained when cloned if the
+// type is refcounted. Both use the latest version of their fn
+struct Handle<'a> {
+    opaque: &'a str,
+    release: String,
+    retain: Option<String>,
+}
+
+fn latest_str(apis: &Apis, name: &str) -> String {
+    match apis.latest(name) {
+        Some(Decl::Fn(decl)) => format!("{}_v{}", decl.name, decl.version.0),
+        _ => panic!("expected fn decl"),
+    }
+}
+
+fn handles(defn: &ApiDefn) -> Vec<Handle<'_>> {
+    let mut handles = vec![];
+    for api in &defn.apis.apis {
+        if let Decl::Fn(decl) = api.latest() {
+            if let Some(opaque) = decl.destructs() {
+                handles.push(Handle {
+                    opaque,
+                    release: format!("{}_v{}", decl.name, decl.version.0),
+                    retain: None,
+                });
+            }
+        }
+    }
+    for decl in &defn.decls {
+        if let Decl::Opaque(decl) = decl.as_ref() {
+            if let (Some(retain), Some(release)) = (decl.retain(), decl.release()) {
+                handles.push(Handle {
+                    opaque: &decl.name,
+                    release: latest_str(&defn.apis, release),
+                    retain: Some(latest_str(&defn.apis, retain)),
+                });
+            }
+        }
+    }
+    handles
+}
+
+fn emit_handles(out: &mut dyn std::fmt::Write, defn: &ApiDefn) -> std::fmt::Result {
+    let handles = handles(defn);
+    if handles.is_empty() {
+        return Ok(());
+    }
+    write!(out, "{}", HANDLES_HEADER)?;
+    for Handle {
+        opaque,
+        release,
+        retain,
+    } in handles
+    {
+        let handle = handle_str(opaque);
+        match &retain {
+            Some(retain) => writeln!(
+                out,
+                "/// A counted reference to a `{}`: retained with `{}` when cloned, and released with `{}` when dropped",
+                opaque, retain, release
+            )?,
+            None => writeln!(
+                out,
+                "/// An owned `{}`: released with `{}` when dropped",
+                opaque, release
+            )?,
+        }
+        writeln!(
+            out,
+            "pub struct {}(core::ptr::NonNull<{}>);",
+            handle, opaque
+        )?;
+        writeln!(out, "impl {} {{", handle)?;
+        writeln!(out, "  /// # Safety")?;
+        writeln!(
+            out,
+            "  /// `p` must be owned by the caller, and not released elsewhere"
+        )?;
+        writeln!(
+            out,
+            "  pub unsafe fn from_raw(p: *mut {}) -> Option<Self> {{ core::ptr::NonNull::new(p).map(Self) }}",
+            opaque
+        )?;
+        writeln!(
+            out,
+            "  pub fn as_ptr(&self) -> *mut {} {{ self.0.as_ptr() }}",
+            opaque
+        )?;
+        writeln!(
+            out,
+            "  pub fn into_raw(self) -> *mut {} {{ let p = self.0.as_ptr(); core::mem::forget(self); p }}",
+            opaque
+        )?;
+        writeln!(out, "}}")?;
+        if let Some(retain) = &retain {
+            writeln!(out, "impl Clone for {} {{", handle)?;
+            writeln!(
+                out,
+                "  fn clone(&self) -> Self {{ unsafe {{ {}(self.0.as_ptr()); }} Self(self.0) }}",
+                retain
+            )?;
+            writeln!(out, "}}")?;
+        }
+        writeln!(out, "impl Drop for {} {{", handle)?;
+        writeln!(
+            out,
+            "  fn drop(&mut self) {{ unsafe {{ {}(self.0.as_ptr()) }} }}",
+            release
+        )?;
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+// Re-exports the declarations of this file without their prefix: e.g.
+// "example::ctx" for "example_ctx"
+// Strict and reserved keywords, including those of later editions
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+// Stripping the prefix can leave a keyword: e.g. "example_type" becomes
+// "r#type", but "self" can't be a raw identifier so "example_self" is "self_"
+fn ident_str(name: &str) -> String {
+    match name {
+        "crate" | "self" | "Self" | "super" => format!("{}_", name),
+        _ if KEYWORDS.contains(&name) => format!("r#{}", name),
+        _ => name.to_string(),
+    }
+}
+
+fn emit_unprefixed_module(
+    out: &mut dyn std::fmt::Write,
+    defn: &ApiDefn,
+    symbol_prefix: &SymbolPrefix,
+    api_forward_to_latest: bool,
+) -> std::fmt::Result {
+    let mut names = vec![];
+    for decl in &defn.decls {
+        match decl.as_ref() {
+            Decl::Import(_) => continue,
+            Decl::Fn(decl) => names.push(format!("{}_v{}", decl.name, decl.version.0)),
+            Decl::Variant(decl) => {
+                names.push(decl.name.clone());
+                names.push(format!("{}_enum", decl.name));
+                if decl.has_payloads() {
+                    names.push(format!("{}_data", decl.name));
+                }
+            }
+            decl => names.push(decl.name()),
+        }
+    }
+    names.extend(handles(defn).iter().map(|h| handle_str(h.opaque)));
+    if api_forward_to_latest {
+        names.extend(defn.apis.apis.iter().map(|api| api.name.clone()));
+    }
+
+    write!(out, "{}", UNPREFIXED_HEADER)?;
+    writeln!(out, "pub mod {} {{", symbol_prefix.module_name())?;
+    for name in &names {
+        if let Some(unprefixed) = symbol_prefix.strip(name) {
+            writeln!(
+                out,
+                "  pub use super::{} as {};",
+                name,
+                ident_str(unprefixed)
+            )?;
+        }
+    }
+    writeln!(out, "}}")
+}
+
 pub fn emit(out: &mut dyn std::fmt::Write, defn: &ApiDefn, options: Options) -> std::fmt::Result {
-    write!(out, "{}", PREAMBLE)?;
+    write!(out, "{}", preamble(defn))?;
+    if let Some(lib) = &defn.library {
+        emit_skip(out, &lib.prefix)?;
+    }
+    if let Some(symbol_prefix) = &defn.symbol_prefix {
+        emit_skip(out, &symbol_prefix.prefix)?;
+    }
     for decl in &defn.decls {
         match decl.as_ref() {
             Decl::Fn(decl) => emit_fn(out, decl)?,
             Decl::Struct(decl) => emit_struct(out, decl)?,
+            Decl::Union(decl) => emit_union(out, decl, &defn.symbols)?,
+            Decl::Variant(decl) => emit_variant(out, decl, &defn.symbols)?,
+            Decl::Callback(decl) => emit_callback(out, decl)?,
+            Decl::Alias(decl) => emit_alias(out, decl)?,
             Decl::Opaque(decl) => emit_opaque(out, decl)?,
             Decl::Const(decl) => emit_const(out, decl)?,
+            Decl::Enum(decl) => emit_enum(out, decl)?,
+            Decl::Flags(decl) => emit_flags(out, decl)?,
+            Decl::Errors(decl) => emit_errors(out, decl)?,
+            Decl::Import(decl) => emit_import(out, decl)?,
         }
     }
     emit_skip(out, &defn.suffix)?;
+    emit_handles(out, defn)?;
 
     // emit api forwarding
     if options.api_forward_to_latest {
         emit_apis(out, &defn.apis)?;
     }
 
+    if let (true, Some(symbol_prefix)) = (options.rust_unprefixed_module, &defn.symbol_prefix) {
+        emit_unprefixed_module(out, defn, symbol_prefix, options.api_forward_to_latest)?;
+    }
+
     write!(out, "{}", POSTAMBLE)?;
     Ok(())
 }
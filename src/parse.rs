@@ -3,6 +3,7 @@ use crate::{Error, Result};
 
 use std::collections::HashMap;
 use std::fmt;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 #[derive(Debug, PartialEq)]
@@ -10,11 +11,15 @@ pub enum Token {
     EndOfFile,
     Ident(String),
     U64(u64),
+    Str(String),
     Fn,
     Struct,
     Opaque,
     Const,
+    Enum,
+    Union,
     Arrow,
+    Shl,
     Punc(char),
 }
 
@@ -25,11 +30,15 @@ impl fmt::Display for Token {
             Token::EndOfFile => write!(f, "<end-of-file>"),
             Token::Ident(_)  => write!(f, "<identifier>"),
             Token::U64(_)    => write!(f, "<u64>"),
+            Token::Str(_)    => write!(f, "<string>"),
             Token::Fn        => write!(f, "'fn'"),
             Token::Struct    => write!(f, "'struct'"),
             Token::Opaque    => write!(f, "'opaque'"),
             Token::Const     => write!(f, "'const'"),
+            Token::Enum      => write!(f, "'enum'"),
+            Token::Union     => write!(f, "'union'"),
             Token::Arrow     => write!(f, "'->'"),
+            Token::Shl       => write!(f, "'<<'"),
             Token::Punc(c)   => write!(f, "'{}'", c),
         }
     }
@@ -68,9 +77,8 @@ impl Tokenizer {
 
     // advance one char
     pub fn advance_char(&mut self) {
-        match self.peek_char() {
-            Some(c) => self.idx += c.len_utf8(),
-            None => (),
+        if let Some(c) = self.peek_char() {
+            self.idx += c.len_utf8();
         }
     }
 
@@ -136,13 +144,11 @@ impl Tokenizer {
         let start = self.idx;
         let mut end = None;
         while let Some(c) = self.peek_char() {
-            if c == '*' {
-                if self.peek_char_nth(1) == Some('/') {
-                    end = Some(self.idx);
-                    self.advance_char();
-                    self.advance_char();
-                    break;
-                }
+            if c == '*' && self.peek_char_nth(1) == Some('/') {
+                end = Some(self.idx);
+                self.advance_char();
+                self.advance_char();
+                break;
             }
             // not-found: proceed as normal
             self.advance_char();
@@ -152,7 +158,7 @@ impl Tokenizer {
         if let Some(end) = end {
             Ok(Some(self.input[start..end].to_string()))
         } else {
-            return Err(self.error("reached <end-of-file> while inside a block-comment"));
+            Err(self.error("reached <end-of-file> while inside a block-comment"))
         }
     }
 
@@ -163,11 +169,23 @@ impl Tokenizer {
             let white = self.scan_white()?;
             skip.push(SkipElem::Whitespace(white));
             if let Some(comm) = self.maybe_scan_linecomment()? {
-                skip.push(SkipElem::LineComment(comm));
+                // "///" is a doc comment, but "////" is not
+                if comm.starts_with('/') && !comm.starts_with("//") {
+                    let line = comm[1..].trim_end();
+                    let line = line.strip_prefix(' ').unwrap_or(line);
+                    skip.push(SkipElem::DocComment(vec![line.to_string()]));
+                } else {
+                    skip.push(SkipElem::LineComment(comm));
+                }
                 continue;
             }
             if let Some(comm) = self.maybe_scan_blockcomment()? {
-                skip.push(SkipElem::BlockComment(comm));
+                // "/** */" is a doc comment, but "/**/" and "/*** */" are not
+                if comm.starts_with('*') && !comm.starts_with("**") && comm != "*" {
+                    skip.push(SkipElem::DocComment(block_doc_lines(&comm[1..])));
+                } else {
+                    skip.push(SkipElem::BlockComment(comm));
+                }
                 continue;
             }
             break;
@@ -189,32 +207,53 @@ impl Tokenizer {
             None => return Ok((skip, Token::EndOfFile)),
         };
 
-        // token '->'
+        // token '->' or '-'
         if c == '-' {
             self.advance_char();
             if self.peek_char() != Some('>') {
-                return Err(self.error("expected '->'"));
+                return Ok((skip, Token::Punc('-')));
             }
             self.advance_char();
             return Ok((skip, Token::Arrow));
         }
 
+        // token '<<'
+        if c == '<' {
+            self.advance_char();
+            if self.peek_char() != Some('<') {
+                return Err(self.error("expected '<<'"));
+            }
+            self.advance_char();
+            return Ok((skip, Token::Shl));
+        }
+
         // token single char punctuation
         if is_punc(c) {
             self.advance_char();
             return Ok((skip, Token::Punc(c)));
         }
 
-        // parse u64 number
+        // parse u64 number: decimal or "0x" hexadecimal
         if c.is_ascii_digit() {
+            let radix = if c == '0' && self.peek_char_nth(1) == Some('x') {
+                self.advance_char();
+                self.advance_char();
+                16
+            } else {
+                10
+            };
+            let digits_idx = self.idx;
             while let Some(c) = self.peek_char() {
-                if !c.is_ascii_digit() {
+                if !c.is_digit(radix) {
                     break;
                 }
                 self.advance_char();
             }
             let s = &self.input[self.tok_idx..self.idx];
-            let n: u64 = match s.parse() {
+            if digits_idx == self.idx {
+                return Err(self.error(&format!("tokenizer read a number with no digits: '{}'", s)));
+            }
+            let n = match u64::from_str_radix(&self.input[digits_idx..self.idx], radix) {
                 Ok(n) => n,
                 Err(_) => {
                     return Err(self.error(&format!(
@@ -226,6 +265,32 @@ impl Tokenizer {
             return Ok((skip, Token::U64(n)));
         }
 
+        // parse string: contents are kept as written, escapes included
+        if c == '"' {
+            self.advance_char();
+            let start = self.idx;
+            loop {
+                match self.peek_char() {
+                    None | Some('\n') => {
+                        return Err(self.error("reached end of line while inside a string"));
+                    }
+                    Some('"') => break,
+                    Some('\\') => {
+                        // only allow escapes that mean the same thing in C and Rust
+                        self.advance_char();
+                        if !matches!(self.peek_char(), Some('\\' | '"' | 'n' | 'r' | 't')) {
+                            return Err(self.error("unsupported escape sequence in string"));
+                        }
+                        self.advance_char();
+                    }
+                    Some(_) => self.advance_char(),
+                }
+            }
+            let s = self.input[start..self.idx].to_string();
+            self.advance_char();
+            return Ok((skip, Token::Str(s)));
+        }
+
         // parse identifier or keyword
         if is_ident_char_start(c) {
             let mut s = String::new();
@@ -244,6 +309,11 @@ impl Tokenizer {
         Err(self.error(&format!("tokenizer read an invalid character: '{}'", c)))
     }
 
+    // source text of the most recently scanned token
+    pub fn tok_str(&self) -> &str {
+        &self.input[self.tok_idx..self.idx]
+    }
+
     fn current_line(&self) -> &str {
         let line_start = &self.input[self.line_start_idx..];
         match line_start.find('\n') {
@@ -284,10 +354,30 @@ fn is_ident_char(c: char) -> bool {
     c.is_ascii_alphanumeric() || c == '_'
 }
 
+// Lines of a "/** */" doc comment, without any leading "*" decoration, a
+// single leading space, or blank lines at the start or end
+fn block_doc_lines(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = text
+        .lines()
+        .map(|l| {
+            let l = l.trim();
+            let l = l.strip_prefix('*').unwrap_or(l);
+            l.strip_prefix(' ').unwrap_or(l).to_string()
+        })
+        .collect();
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    let first = lines.iter().position(|l| !l.is_empty());
+    lines.split_off(first.unwrap_or(lines.len()))
+}
+
 #[rustfmt::skip]
 fn is_punc(c: char) -> bool {
     c == '[' || c == ']' || c == '(' || c == ')' || c == '{' || c == '}' ||
-    c == '*' || c == ':' || c == ',' || c == ';' || c == '='
+    c == '*' || c == ':' || c == ',' || c == ';' || c == '=' || c == '+' ||
+    c == '/' || c == '&' || c == '|' || c == '?' || c == '!' || c == '#' ||
+    c == '@'
 }
 
 fn tok_ident_or_keyword(s: String) -> Token {
@@ -296,6 +386,8 @@ fn tok_ident_or_keyword(s: String) -> Token {
         "struct" => Token::Struct,
         "opaque" => Token::Opaque,
         "const" => Token::Const,
+        "enum" => Token::Enum,
+        "union" => Token::Union,
         _ => Token::Ident(s),
     }
 }
@@ -304,16 +396,36 @@ pub struct Parser {
     tokenizer: Tokenizer,
     skip: Skip,
     tok: Token,
+    symbols: HashMap<String, Rc<Decl>>,
+    // imports are relative to the directory of the file being parsed
+    dir: PathBuf,
+    // files currently being parsed, to detect import cycles
+    importing: Vec<PathBuf>,
+    // prefix for the names declared in this file, if any
+    prefix: String,
 }
 
 impl Parser {
     pub fn new(inp: &str, srcname: Option<&str>) -> Result<Self> {
         let mut tokenizer = Tokenizer::new(inp, srcname);
         let (skip, tok) = tokenizer.next_tok()?;
+        let path = srcname.map(Path::new);
+        let dir = path
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let importing = path
+            .and_then(|p| p.canonicalize().ok())
+            .into_iter()
+            .collect();
         Ok(Self {
             tokenizer,
             skip,
             tok,
+            symbols: HashMap::new(),
+            dir,
+            importing,
+            prefix: String::new(),
         })
     }
 
@@ -333,6 +445,30 @@ impl Parser {
         }
     }
 
+    // Declared names get the prefix of the file
+    fn expect_decl_name(&mut self) -> Result<String> {
+        let name = self.expect_ident()?;
+        Ok(format!("{}{}", self.prefix, name))
+    }
+
+    // Constants are macros in C, so their prefix is upper-cased
+    fn expect_const_name(&mut self) -> Result<String> {
+        let name = self.expect_ident()?;
+        Ok(format!("{}{}", self.prefix.to_uppercase(), name))
+    }
+
+    // Names refer to a declaration of this file if there is one, and otherwise
+    // to one imported as written. Unknown names are assumed to be declared
+    // later in this file
+    fn resolve_name(&self, name: String, prefix: &str) -> String {
+        let prefixed = format!("{}{}", prefix, name);
+        if self.symbols.contains_key(&prefixed) || !self.symbols.contains_key(&name) {
+            prefixed
+        } else {
+            name
+        }
+    }
+
     fn expect_ident(&mut self) -> Result<String> {
         if let Token::Ident(name) = &self.tok {
             let name = name.clone();
@@ -347,6 +483,21 @@ impl Parser {
         }
     }
 
+    // Contextual keywords are only keywords at the start of a decl, so they
+    // remain usable as field and argument names
+    fn tok_is_contextual_keyword(&self, kw: &str) -> bool {
+        matches!(&self.tok, Token::Ident(s) if s == kw)
+    }
+
+    fn expect_contextual_keyword(&mut self, kw: &str) -> Result<()> {
+        if !self.tok_is_contextual_keyword(kw) {
+            return Err(self
+                .tokenizer
+                .error(&format!("expected '{}', found {}", kw, self.tok)));
+        }
+        self.next_tok()
+    }
+
     fn expect_u64(&mut self) -> Result<u64> {
         if let Token::U64(num) = &self.tok {
             let num = *num;
@@ -359,6 +510,139 @@ impl Parser {
         }
     }
 
+    // intlit = "-"? u64
+    fn parse_intlit(&mut self, typ: &BaseType) -> Result<IntLit> {
+        let neg = matches!(self.tok, Token::Punc('-'));
+        if neg {
+            self.next_tok()?;
+        }
+        self.parse_intlit_digits(typ, neg)
+    }
+
+    // the u64 part of an intlit, after any "-" has been consumed
+    fn parse_intlit_digits(&mut self, typ: &BaseType, neg: bool) -> Result<IntLit> {
+        let mut text = if neg { "-".to_string() } else { "".to_string() };
+        text += self.tokenizer.tok_str();
+        let mag = self.expect_u64()? as i128;
+        let val = if neg { -mag } else { mag };
+        self.check_fits(typ, val)?;
+        Ok(IntLit { val, text })
+    }
+
+    fn check_fits(&self, typ: &BaseType, val: i128) -> Result<()> {
+        if !int_fits(typ, val) {
+            return Err(self.tokenizer.error(&format!(
+                "value {} is out of range for the declared type",
+                val
+            )));
+        }
+        Ok(())
+    }
+
+    // constexpr = binexpr(0)
+    fn parse_constexpr(&mut self, typ: &BaseType) -> Result<ConstExpr> {
+        let (expr, val) = self.parse_binexpr(typ, 0)?;
+        Ok(ConstExpr { expr, val })
+    }
+
+    // binexpr(n) = binexpr(n+1) (binop(n) binexpr(n+1))*
+    fn parse_binexpr(&mut self, typ: &BaseType, level: usize) -> Result<(Expr, i128)> {
+        if level == BINOP_LEVELS.len() {
+            return self.parse_unaryexpr(typ);
+        }
+        let (mut lhs, mut lval) = self.parse_binexpr(typ, level + 1)?;
+        while let Some(op) = self.tok_binop() {
+            if !BINOP_LEVELS[level].contains(&op) {
+                break;
+            }
+            self.next_tok()?;
+            let (rhs, rval) = self.parse_binexpr(typ, level + 1)?;
+            lval = match eval_binop(op, lval, rval) {
+                Some(val) => val,
+                None => {
+                    return Err(self.tokenizer.error(&format!(
+                        "invalid constant expression: {} {} {}",
+                        lval,
+                        op.as_str(),
+                        rval
+                    )))
+                }
+            };
+            self.check_fits(typ, lval)?;
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(rhs));
+        }
+        Ok((lhs, lval))
+    }
+
+    fn tok_binop(&self) -> Option<BinOp> {
+        match self.tok {
+            Token::Punc('+') => Some(BinOp::Add),
+            Token::Punc('-') => Some(BinOp::Sub),
+            Token::Punc('*') => Some(BinOp::Mul),
+            Token::Punc('/') => Some(BinOp::Div),
+            Token::Shl => Some(BinOp::Shl),
+            Token::Punc('&') => Some(BinOp::And),
+            Token::Punc('|') => Some(BinOp::Or),
+            _ => None,
+        }
+    }
+
+    // unaryexpr = "-" u64 | "-" unaryexpr | primaryexpr
+    fn parse_unaryexpr(&mut self, typ: &BaseType) -> Result<(Expr, i128)> {
+        if !matches!(self.tok, Token::Punc('-')) {
+            return self.parse_primaryexpr(typ);
+        }
+        self.next_tok()?;
+        // negative numbers stay a single literal
+        if matches!(self.tok, Token::U64(_)) {
+            let lit = self.parse_intlit_digits(typ, true)?;
+            let val = lit.val;
+            return Ok((Expr::Lit(lit), val));
+        }
+        let (expr, val) = self.parse_unaryexpr(typ)?;
+        self.check_fits(typ, -val)?;
+        Ok((Expr::Neg(Box::new(expr)), -val))
+    }
+
+    // primaryexpr = u64 | ident | "(" constexpr ")"
+    fn parse_primaryexpr(&mut self, typ: &BaseType) -> Result<(Expr, i128)> {
+        match &self.tok {
+            Token::U64(_) => {
+                let lit = self.parse_intlit_digits(typ, false)?;
+                let val = lit.val;
+                Ok((Expr::Lit(lit), val))
+            }
+            Token::Ident(written) => {
+                let name = self.resolve_name(written.clone(), &self.prefix.to_uppercase());
+                let (ctyp, val) = match self.symbols.get(&name).map(Rc::as_ref) {
+                    Some(Decl::Const(ConstDecl {
+                        val: ConstValue::Int(ctyp, expr),
+                        ..
+                    })) => (ctyp.clone(), expr.val),
+                    _ => {
+                        return Err(self.tokenizer.error(&format!(
+                            "'{}' is not a previously defined integer constant",
+                            written
+                        )))
+                    }
+                };
+                self.check_fits(typ, val)?;
+                self.next_tok()?;
+                Ok((Expr::Const(name, ctyp), val))
+            }
+            Token::Punc('(') => {
+                self.next_tok()?;
+                let (expr, val) = self.parse_binexpr(typ, 0)?;
+                self.expect(Token::Punc(')'))?;
+                Ok((Expr::Paren(Box::new(expr)), val))
+            }
+            _ => Err(self.tokenizer.error(&format!(
+                "expected <constant-expression>, found {}",
+                self.tok
+            ))),
+        }
+    }
+
     // basetype = ident | "u8" | "i8" | ... etc ...
     fn parse_basetype(&mut self) -> Result<BaseType> {
         if !matches!(self.tok, Token::Ident(_)) {
@@ -376,24 +660,79 @@ impl Parser {
             "i32" => BaseType::I32,
             "u64" => BaseType::U64,
             "i64" => BaseType::I64,
-            _ => BaseType::Struct(type_str),
+            "bool" => BaseType::Bool,
+            "f32" => BaseType::F32,
+            "f64" => BaseType::F64,
+            "usize" => BaseType::USize,
+            "isize" => BaseType::ISize,
+            "c_char" => BaseType::CChar,
+            "c_schar" => BaseType::CSChar,
+            "c_uchar" => BaseType::CUChar,
+            "c_short" => BaseType::CShort,
+            "c_ushort" => BaseType::CUShort,
+            "c_int" => BaseType::CInt,
+            "c_uint" => BaseType::CUInt,
+            "c_long" => BaseType::CLong,
+            "c_ulong" => BaseType::CULong,
+            "c_longlong" => BaseType::CLongLong,
+            "c_ulonglong" => BaseType::CULongLong,
+            _ => BaseType::Struct(self.resolve_name(type_str, &self.prefix)),
         };
         Ok(base)
     }
 
-    // type = "*" type | "[" type ";" number "]" basetype
+    // mutability = ("const" | "mut")?
+    fn parse_mutability(&mut self) -> Result<Mutability> {
+        if matches!(self.tok, Token::Const) {
+            self.next_tok()?;
+            return Ok(Mutability::Const);
+        }
+        if self.tok_is_contextual_keyword("mut") {
+            self.next_tok()?;
+        }
+        Ok(Mutability::Mut)
+    }
+
+    // pointer = "*" "?"? mutability type "!"?
+    //
+    // A "!" binds to the innermost pointer not marked "?": e.g. "**?u8!" is a
+    // non-null pointer to a nullable pointer
+    fn parse_pointer(&mut self) -> Result<Type> {
+        self.expect(Token::Punc('*'))?;
+        let nullable = matches!(self.tok, Token::Punc('?'));
+        if nullable {
+            self.next_tok()?;
+        }
+        let mutability = self.parse_mutability()?;
+        let typ = self.parse_type()?;
+        let nonnull = !nullable && matches!(self.tok, Token::Punc('!'));
+        if nonnull {
+            self.next_tok()?;
+        }
+        let nullability = if nullable {
+            Nullability::Nullable
+        } else if nonnull {
+            Nullability::NonNull
+        } else {
+            Nullability::Unspecified
+        };
+        Ok(Type::Pointer(mutability, nullability, Box::new(typ)))
+    }
+
+    // type = pointer | "[" type ";" constexpr "]" | fntype | basetype
     fn parse_type(&mut self) -> Result<Type> {
         let typ = if matches!(self.tok, Token::Punc('*')) {
-            self.next_tok()?;
-            let typ = self.parse_type()?;
-            Type::Pointer(Box::new(typ))
+            self.parse_pointer()?
         } else if matches!(self.tok, Token::Punc('[')) {
             self.next_tok()?;
             let typ = self.parse_type()?;
             self.expect(Token::Punc(';'))?;
-            let num = self.expect_u64()?;
+            let len = self.parse_constexpr(&BaseType::U64)?;
             self.expect(Token::Punc(']'))?;
-            Type::Array(Box::new(typ), num)
+            Type::Array(Box::new(typ), len)
+        } else if matches!(self.tok, Token::Fn) {
+            let typ = self.parse_fntype()?;
+            Type::FnPtr(Box::new(typ))
         } else {
             let typ = self.parse_basetype()?;
             Type::Base(typ)
@@ -408,22 +747,344 @@ impl Parser {
         Ok(typ)
     }
 
-    // field = ident ":" type
-    fn maybe_parse_field(&mut self) -> Result<Option<Field>> {
-        if !matches!(self.tok, Token::Ident(_)) {
+    // fntype = "fn" "(" fields ")" ret
+    fn parse_fntype(&mut self) -> Result<FnType> {
+        self.expect(Token::Fn)?;
+        self.expect(Token::Punc('('))?;
+        let args = self.parse_fields("fn pointer arg")?;
+        self.expect(Token::Punc(')'))?;
+        let (ret, ret_docs) = self.parse_ret()?;
+        Ok(FnType {
+            args,
+            ret,
+            ret_docs,
+        })
+    }
+
+    // Returns the skip before the item (without its doc comments), the doc
+    // comments, and the attributes. Doc comments may come before or after
+    // the attributes, and other comments between them are kept in the skip.
+    //
+    // attrs = attr*
+    fn parse_docs_and_attrs(&mut self) -> Result<(Skip, Vec<String>, Vec<Attribute>)> {
+        let mut prefix = self.skip.clone();
+        let mut docs = take_docs(&mut prefix);
+        let mut attrs: Vec<Attribute> = Vec::new();
+        while matches!(self.tok, Token::Punc('#')) {
+            let attr = self.parse_attr()?;
+            if attrs.iter().any(|a| a.name == attr.name) {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("duplicate attribute '{}'", attr.name)));
+            }
+            attrs.push(attr);
+            // the attribute isn't emitted, so neither is the rest of its line
+            let mut between = self.skip.trim_start();
+            docs.extend(take_docs(&mut between));
+            prefix.0.extend(between.0);
+        }
+        Ok((prefix, docs, attrs))
+    }
+
+    // attr = "#" "[" attrname ("(" attrargs ")")? "]"
+    fn parse_attr(&mut self) -> Result<Attribute> {
+        self.expect(Token::Punc('#'))?;
+        self.expect(Token::Punc('['))?;
+        let name = self.parse_attrname()?;
+        let mut args = Vec::new();
+        if matches!(self.tok, Token::Punc('(')) {
+            self.next_tok()?;
+            args = self.parse_attrargs()?;
+            self.expect(Token::Punc(')'))?;
+        }
+        let attr = Attribute { name, args };
+        if !attr.is_namespaced() && !ATTRIBUTES.iter().any(|(name, _)| *name == attr.name) {
+            return Err(self
+                .tokenizer
+                .error(&format!("unknown attribute '{}'", attr.name)));
+        }
+        self.expect(Token::Punc(']'))?;
+        Ok(attr)
+    }
+
+    // Checks that dandiya's own attributes are attached to something they
+    // apply to: e.g. "struct" or "field"
+    fn check_attr_sites(&self, attrs: &[Attribute], site: &str) -> Result<()> {
+        for attr in attrs {
+            match ATTRIBUTES.iter().find(|(name, _)| *name == attr.name) {
+                Some((_, sites)) if !sites.contains(&site) => {
+                    return Err(self.tokenizer.error(&format!(
+                        "attribute '{}' is not allowed on a {}",
+                        attr.name, site
+                    )))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    // attrname = ident ("::" ident)*
+    fn parse_attrname(&mut self) -> Result<String> {
+        let mut name = self.expect_ident()?;
+        while matches!(self.tok, Token::Punc(':')) {
+            self.next_tok()?;
+            self.expect(Token::Punc(':'))?;
+            name += "::";
+            name += &self.expect_ident()?;
+        }
+        Ok(name)
+    }
+
+    // attrargs = "" | attrarg ("," attrarg)* ","?
+    // attrarg = (ident "=")? (intlit | string | ident)
+    fn parse_attrargs(&mut self) -> Result<Vec<AttrArg>> {
+        let mut args = Vec::new();
+        loop {
+            let arg = match &self.tok {
+                Token::Ident(_) => {
+                    let ident = self.expect_ident()?;
+                    if matches!(self.tok, Token::Punc('=')) {
+                        self.next_tok()?;
+                        let val = self.parse_attrvalue()?;
+                        AttrArg {
+                            key: Some(ident),
+                            val,
+                        }
+                    } else {
+                        AttrArg {
+                            key: None,
+                            val: AttrValue::Ident(ident),
+                        }
+                    }
+                }
+                Token::U64(_) | Token::Str(_) | Token::Punc('-') => AttrArg {
+                    key: None,
+                    val: self.parse_attrvalue()?,
+                },
+                _ => return Ok(args),
+            };
+            args.push(arg);
+            if !matches!(self.tok, Token::Punc(',')) {
+                return Ok(args);
+            }
+            self.next_tok()?;
+        }
+    }
+
+    fn parse_attrvalue(&mut self) -> Result<AttrValue> {
+        match &self.tok {
+            Token::Ident(_) => Ok(AttrValue::Ident(self.expect_ident()?)),
+            Token::Str(s) => {
+                let s = s.clone();
+                self.next_tok()?;
+                Ok(AttrValue::Str(s))
+            }
+            _ => Ok(AttrValue::Int(self.parse_intlit(&BaseType::I64)?)),
+        }
+    }
+
+    // field = docs attrs ident ":" type ("{" bitranges "}")? ("=" constexpr)?
+    //
+    // The site is what the fields are: e.g. "field" or "arg"
+    fn maybe_parse_field(&mut self, site: &str) -> Result<Option<Field>> {
+        let (_, docs, attrs) = self.parse_docs_and_attrs()?;
+        if attrs.is_empty() && !matches!(self.tok, Token::Ident(_)) {
+            if !docs.is_empty() {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("doc comment with no {} after it", site)));
+            }
             return Ok(None);
         }
+        self.check_attr_sites(&attrs, site)?;
         let name = self.expect_ident()?;
         self.expect(Token::Punc(':'))?;
         let typ = self.parse_type()?;
-        Ok(Some(Field { name, typ }))
+        let bits = if site == "field" && matches!(self.tok, Token::Punc('{')) {
+            self.next_tok()?;
+            let bits = self.parse_bits(&name, &typ)?;
+            self.expect(Token::Punc('}'))?;
+            Some(bits)
+        } else {
+            None
+        };
+        let default = if site == "field" && matches!(self.tok, Token::Punc('=')) {
+            self.next_tok()?;
+            Some(self.parse_field_default(&name, &typ)?)
+        } else {
+            None
+        };
+        let field = Field {
+            docs,
+            attrs,
+            name,
+            typ,
+            default,
+            bits,
+        };
+        self.check_direction(&field)?;
+        Ok(Some(field))
+    }
+
+    // bitranges = bitrange ("," bitrange)* ","?
+    // bitrange = ident ":" intlit ("@" intlit)?
+    //
+    // Ranges are the width in bits, and follow on from the previous range
+    // unless their offset is given
+    fn parse_bits(&mut self, name: &str, typ: &Type) -> Result<Bits> {
+        let repr = match typ {
+            Type::Base(base) => self.resolve_alias(base.clone()),
+            _ => BaseType::Bool, // not an integer
+        };
+        let bits = match int_range(&repr) {
+            Some((0, max)) => max.count_ones(),
+            _ => {
+                return Err(self.tokenizer.error(&format!(
+                    "'{}' can't have bit ranges: only unsigned fixed-width integer fields can",
+                    name
+                )))
+            }
+        };
+        let mut ranges: Vec<BitRange> = Vec::new();
+        while matches!(self.tok, Token::Ident(_)) {
+            let range_name = self.expect_ident()?;
+            self.expect(Token::Punc(':'))?;
+            let width = self.parse_intlit(&BaseType::U8)?.val;
+            let offset = if matches!(self.tok, Token::Punc('@')) {
+                self.next_tok()?;
+                self.parse_intlit(&BaseType::U8)?.val
+            } else {
+                ranges.last().map_or(0, |r| (r.offset + r.width) as i128)
+            };
+            if width == 0 || offset + width > bits as i128 {
+                return Err(self.tokenizer.error(&format!(
+                    "bit range '{}' doesn't fit in the {} bits of '{}'",
+                    range_name, bits, name
+                )));
+            }
+            let range = BitRange {
+                name: range_name,
+                offset: offset as u32,
+                width: width as u32,
+            };
+            if let Some(other) = ranges.iter().find(|r| r.name == range.name) {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("duplicate bit range '{}'", other.name)));
+            }
+            let overlapping = ranges.iter().find(|r| {
+                r.offset < range.offset + range.width && range.offset < r.offset + r.width
+            });
+            if let Some(other) = overlapping {
+                return Err(self.tokenizer.error(&format!(
+                    "bit range '{}' overlaps '{}'",
+                    range.name, other.name
+                )));
+            }
+            ranges.push(range);
+            if !matches!(self.tok, Token::Punc(',')) {
+                break;
+            }
+            self.next_tok()?;
+        }
+        if ranges.is_empty() {
+            return Err(self
+                .tokenizer
+                .error(&format!("'{}' must have at least one bit range", name)));
+        }
+        Ok(Bits { repr, ranges })
+    }
+
+    fn parse_field_default(&mut self, name: &str, typ: &Type) -> Result<FieldDefault> {
+        let resolved = match typ {
+            Type::Base(base) => self.resolve_alias(base.clone()),
+            _ => BaseType::Bool, // not an integer
+        };
+        if int_range(&resolved).is_none() {
+            return Err(self.tokenizer.error(&format!(
+                "'{}' can't have a default: only fixed-width integer fields can",
+                name
+            )));
+        }
+        let expr = self.parse_constexpr(&resolved)?;
+        Ok(FieldDefault::Int(resolved, expr))
+    }
+
+    // A struct field without a default of its own takes those of its type
+    fn nested_default(&self, typ: &Type) -> Option<FieldDefault> {
+        let Type::Base(base) = typ else {
+            return None;
+        };
+        let BaseType::Struct(name) = self.resolve_alias(base.clone()) else {
+            return None;
+        };
+        match self.symbols.get(&name).map(Rc::as_ref) {
+            Some(Decl::Struct(decl)) if decl.has_defaults() => Some(FieldDefault::Struct(name)),
+            _ => None,
+        }
+    }
+
+    // Checks that an arg written through by the callee is a mutable pointer to
+    // something that has a value
+    fn check_direction(&self, field: &Field) -> Result<()> {
+        let directions: Vec<_> = field
+            .attrs
+            .iter()
+            .filter(|a| matches!(a.name.as_str(), "in" | "out" | "inout"))
+            .collect();
+        if directions.len() > 1 {
+            return Err(self
+                .tokenizer
+                .error(&format!("'{}' has conflicting directions", field.name)));
+        }
+        if directions.iter().any(|a| !a.args.is_empty()) {
+            return Err(self
+                .tokenizer
+                .error(&format!("'{}' takes no arguments", directions[0].name)));
+        }
+        if !matches!(field.direction(), Some(Direction::Out | Direction::InOut)) {
+            return Ok(());
+        }
+        match &field.typ {
+            Type::Pointer(Mutability::Mut, _, pointee) if !self.is_opaque(pointee) => Ok(()),
+            _ => Err(self.tokenizer.error(&format!(
+                "'{}' is written by the callee, so it must be a '*mut' pointer to a non-opaque type",
+                field.name
+            ))),
+        }
+    }
+
+    // An opaque type named directly, rather than through an alias
+    fn is_opaque_decl(&self, t: &Type) -> bool {
+        match t {
+            Type::Base(BaseType::Struct(name)) => matches!(
+                self.symbols.get(name).map(Rc::as_ref),
+                Some(Decl::Opaque(_))
+            ),
+            _ => false,
+        }
+    }
+
+    // An opaque type without a declared size: it has no value of its own
+    fn is_opaque(&self, t: &Type) -> bool {
+        let Type::Base(base) = t else {
+            return false;
+        };
+        let BaseType::Struct(name) = self.resolve_alias(base.clone()) else {
+            return false;
+        };
+        matches!(
+            self.symbols.get(&name).map(Rc::as_ref),
+            Some(Decl::Opaque(decl)) if decl.size.is_none()
+        )
     }
 
     // fields = "" | field ("," field)* ","?
-    fn parse_fields(&mut self) -> Result<Vec<Field>> {
+    fn parse_fields(&mut self, site: &str) -> Result<Vec<Field>> {
         let mut args = Vec::new();
 
-        match self.maybe_parse_field()? {
+        match self.maybe_parse_field(site)? {
             Some(f) => args.push(f),
             None => return Ok(args),
         }
@@ -433,18 +1094,19 @@ impl Parser {
                 return Ok(args);
             }
             self.next_tok()?;
-            match self.maybe_parse_field()? {
+            match self.maybe_parse_field(site)? {
                 Some(f) => args.push(f),
                 None => return Ok(args),
             }
         }
     }
 
-    // ret = ("->" type)?
-    fn parse_ret(&mut self) -> Result<ReturnType> {
+    // ret = (docs "->" type)?
+    fn parse_ret(&mut self) -> Result<(ReturnType, Vec<String>)> {
         if !matches!(self.tok, Token::Arrow) {
-            return Ok(ReturnType::None);
+            return Ok((ReturnType::None, vec![]));
         }
+        let docs = take_docs(&mut self.skip.clone());
         self.next_tok()?;
         let typ = self.parse_type()?;
         if type_is_array_recursively(&typ) {
@@ -452,110 +1114,1176 @@ impl Parser {
                 .tokenizer
                 .error("return type is not allowed to be an array"));
         }
-        Ok(ReturnType::Some(typ))
+        Ok((ReturnType::Some(typ), docs))
     }
 
     // version = "v" number
     fn parse_version(&mut self) -> Result<Version> {
         let v = self.expect_ident()?;
-        if v.chars().next() != Some('v') {
+        if !v.starts_with('v') {
             return Err(self.tokenizer.error("not a version identifier"));
         }
         let num: u64 = match v[1..].parse() {
             Ok(v) => v,
             Err(_) => return Err(self.tokenizer.error("not a version number")),
         };
-        return Ok(Version(num));
+        Ok(Version(num))
     }
 
     // func = "fn" "(" ident ")" ident "(" args ")" ret ";"
-    fn parse_fn(&mut self) -> Result<Decl> {
-        let prefix = self.skip.clone();
+    fn parse_fn(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        mut attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
         self.expect(Token::Fn)?;
         self.expect(Token::Punc('('))?;
         let version = self.parse_version()?;
         self.expect(Token::Punc(')'))?;
-        let name = self.expect_ident()?;
+        let name = self.expect_decl_name()?;
         self.expect(Token::Punc('('))?;
-        let args = self.parse_fields()?;
+        let args = self.parse_fields("arg")?;
         self.expect(Token::Punc(')'))?;
-        let ret = self.parse_ret()?;
+        self.check_len_of(&args)?;
+        let (ret, ret_docs) = self.parse_ret()?;
+        self.resolve_returns_status(&mut attrs, &ret)?;
+        self.resolve_ownership(&mut attrs, &args, &ret)?;
         self.expect(Token::Punc(';'))?;
         Ok(Decl::Fn(FuncDecl {
             prefix,
+            docs,
+            attrs,
             name,
             args,
             ret,
+            ret_docs,
             version,
         }))
     }
 
-    // struct = "struct" ident "{" fields "}"
-    fn parse_struct(&mut self) -> Result<Decl> {
-        let prefix = self.skip.clone();
-        self.expect(Token::Struct)?;
-        let name = self.expect_ident()?;
-        self.expect(Token::Punc('{'))?;
-        let fields = self.parse_fields()?;
-        self.expect(Token::Punc('}'))?;
-        Ok(Decl::Struct(StructDecl {
-            prefix,
-            name,
-            fields,
-        }))
-    }
-
-    // opaque = "opaque" ident ";"
-    fn parse_opaque(&mut self) -> Result<Decl> {
-        let prefix = self.skip.clone();
-        self.expect(Token::Opaque)?;
-        let name = self.expect_ident()?;
-        self.expect(Token::Punc(';'))?;
-        Ok(Decl::Opaque(OpaqueDecl { prefix, name }))
-    }
-
-    // const = "const" ident "=" u64 ";"
-    fn parse_const(&mut self) -> Result<Decl> {
-        let prefix = self.skip.clone();
-        self.expect(Token::Const)?;
-        let name = self.expect_ident()?;
-        self.expect(Token::Punc('='))?;
-        let val = self.expect_u64()?;
-        self.expect(Token::Punc(';'))?;
-        Ok(Decl::Const(ConstDecl { prefix, name, val }))
-    }
-
-    // decl = func | struct | opaque | const
-    fn maybe_parse_decl(&mut self) -> Result<Option<Decl>> {
-        match self.tok {
-            Token::Fn => Ok(Some(self.parse_fn()?)),
-            Token::Struct => Ok(Some(self.parse_struct()?)),
-            Token::Opaque => Ok(Some(self.parse_opaque()?)),
-            Token::Const => Ok(Some(self.parse_const()?)),
-            _ => Ok(None),
+    // Checks that "#[returns_status(name)]" names an error domain which is (or
+    // has the same repr as) the return type. The name is resolved in place
+    fn resolve_returns_status(&self, attrs: &mut [Attribute], ret: &ReturnType) -> Result<()> {
+        let Some(attr) = attrs.iter_mut().find(|a| a.name == "returns_status") else {
+            return Ok(());
+        };
+        let name = match attr.args.as_mut_slice() {
+            [AttrArg {
+                key: None,
+                val: AttrValue::Ident(name),
+            }] => name,
+            _ => {
+                return Err(self.tokenizer.error(
+                    "'returns_status' takes the name of an error domain: e.g. '#[returns_status(example_err)]'",
+                ))
+            }
+        };
+        *name = self.resolve_name(name.clone(), &self.prefix);
+        let repr = match self.symbols.get(name.as_str()).map(Rc::as_ref) {
+            Some(Decl::Errors(decl)) => &decl.repr,
+            _ => {
+                return Err(self.tokenizer.error(&format!(
+                    "'{}' is not a previously defined error domain",
+                    name
+                )))
+            }
+        };
+        // an alias of the error domain itself isn't accepted: the wrappers
+        // need to know whether to convert the returned value
+        let ok = match ret {
+            ReturnType::Some(Type::Base(base)) => {
+                *base == BaseType::Struct(name.clone()) || self.resolve_alias(base.clone()) == *repr
+            }
+            _ => false,
+        };
+        if !ok {
+            return Err(self.tokenizer.error(&format!(
+                "the return type must be '{}' to return its status",
+                name
+            )));
         }
+        Ok(())
     }
 
-    pub fn parse(&mut self) -> Result<ApiDefn> {
-        let mut symbols = HashMap::new();
-        let mut apis = Apis::new();
-        let mut decls = vec![];
-
-        while let Some(decl) = self.maybe_parse_decl()? {
-            let decl = Rc::new(decl);
-            let name = decl.name();
-
-            // add the symbol
-            if symbols.contains_key(&name) {
+    // Checks that a "#[constructor]" returns a new opaque object, and that a
+    // "#[destructor(name)]" releases one. The name is resolved in place
+    fn resolve_ownership(
+        &self,
+        attrs: &mut [Attribute],
+        args: &[Field],
+        ret: &ReturnType,
+    ) -> Result<()> {
+        if let Some(attr) = find_attr(attrs, "constructor") {
+            if !attr.args.is_empty() {
+                return Err(self.tokenizer.error("'constructor' takes no arguments"));
+            }
+            if !matches!(ret, ReturnType::Some(Type::Pointer(Mutability::Mut, _, pointee)) if self.is_opaque_decl(pointee))
+            {
                 return Err(self
                     .tokenizer
-                    .error(&format!("duplicate symbol '{}'", name)));
+                    .error("a constructor must return a '*mut' pointer to an opaque type"));
             }
-            symbols.insert(name, decl.clone());
-
-            // add the api
-            if let Decl::Fn(func) = decl.as_ref() {
-                if let Some(_) = apis.insert(func.name.to_string(), func.version, decl.clone()) {
+        }
+        let Some(attr) = attrs.iter_mut().find(|a| a.name == "destructor") else {
+            return Ok(());
+        };
+        let name = match attr.args.as_mut_slice() {
+            [AttrArg {
+                key: None,
+                val: AttrValue::Ident(name),
+            }] => name,
+            _ => return Err(self.tokenizer.error(
+                "'destructor' takes the name of an opaque type: e.g. '#[destructor(example_ctx)]'",
+            )),
+        };
+        *name = self.resolve_name(name.clone(), &self.prefix);
+        let released = Type::Base(BaseType::Struct(name.clone()));
+        if !self.is_opaque_decl(&released) {
+            return Err(self.tokenizer.error(&format!(
+                "'{}' is not a previously defined opaque type",
+                name
+            )));
+        }
+        let ok = match (args, ret) {
+            ([arg], ReturnType::None) => {
+                matches!(&arg.typ, Type::Pointer(Mutability::Mut, _, pointee) if **pointee == released)
+            }
+            _ => false,
+        };
+        if !ok {
+            return Err(self.tokenizer.error(&format!(
+                "a destructor of '{}' must take a single '*mut {}' arg and return nothing",
+                name, name
+            )));
+        }
+        Ok(())
+    }
+
+    // Checks that "#[len_of(buf)]" names a pointer arg of the same fn, which
+    // has no other length, and is on an unsigned integer arg
+    fn check_len_of(&self, args: &[Field]) -> Result<()> {
+        let mut ptrs = vec![];
+        for arg in args {
+            let Some(attr) = find_attr(&arg.attrs, "len_of") else {
+                continue;
+            };
+            let ptr = match attr.args.as_slice() {
+                [AttrArg {
+                    key: None,
+                    val: AttrValue::Ident(ptr),
+                }] => ptr,
+                _ => {
+                    return Err(self
+                        .tokenizer
+                        .error("'len_of' takes the name of an arg: e.g. '#[len_of(buf)]'"))
+                }
+            };
+            let pointee = match args.iter().find(|a| &a.name == ptr).map(|a| &a.typ) {
+                Some(Type::Pointer(_, _, pointee)) => pointee,
+                _ => {
+                    return Err(self
+                        .tokenizer
+                        .error(&format!("'len_of({})' must name a pointer arg", ptr)))
+                }
+            };
+            if self.is_opaque(pointee) {
+                return Err(self.tokenizer.error(&format!(
+                    "'{}' points to an opaque type, so it has no length",
+                    ptr
+                )));
+            }
+            let unsigned = match &arg.typ {
+                Type::Base(base) => matches!(
+                    self.resolve_alias(base.clone()),
+                    BaseType::U8
+                        | BaseType::U16
+                        | BaseType::U32
+                        | BaseType::U64
+                        | BaseType::USize
+                        | BaseType::CUShort
+                        | BaseType::CUInt
+                        | BaseType::CULong
+                        | BaseType::CULongLong
+                ),
+                _ => false,
+            };
+            if !unsigned {
+                return Err(self.tokenizer.error(&format!(
+                    "the length of '{}' must be an unsigned integer",
+                    ptr
+                )));
+            }
+            if ptrs.contains(&ptr) {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("'{}' has more than one length", ptr)));
+            }
+            ptrs.push(ptr);
+        }
+        Ok(())
+    }
+
+    // struct = "struct" ident "{" fields "}"
+    fn parse_struct(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect(Token::Struct)?;
+        self.check_layout_attrs(&attrs)?;
+        let name = self.expect_decl_name()?;
+        self.expect(Token::Punc('{'))?;
+        let mut fields = self.parse_fields("field")?;
+        for f in fields.iter_mut().filter(|f| f.default.is_none()) {
+            f.default = self.nested_default(&f.typ);
+        }
+        for f in fields.iter().filter(|f| f.is_pad()) {
+            if !matches!(&f.typ, Type::Array(t, _) if matches!(t.as_ref(), Type::Base(BaseType::U8)))
+            {
+                return Err(self
+                    .tokenizer
+                    .error("padding must be '#[pad] name: [u8; N]'"));
+            }
+            if !find_attr(&f.attrs, "pad").is_some_and(|a| a.args.is_empty()) {
+                return Err(self.tokenizer.error("'pad' takes no arguments"));
+            }
+        }
+        let decl = StructDecl {
+            prefix,
+            docs,
+            attrs,
+            name,
+            fields,
+        };
+        // checked as emitted, since "#[pad]" fields are renamed "_padN"
+        let names = decl.field_names();
+        for (i, name) in names.iter().enumerate() {
+            if names[..i].contains(name) {
+                return Err(self.tokenizer.error(&format!("duplicate field '{}'", name)));
+            }
+        }
+        // Rust rejects packed types that contain aligned ones
+        if decl.packed() && decl.fields.iter().any(|f| self.contains_aligned(&f.typ)) {
+            return Err(self
+                .tokenizer
+                .error("a packed struct cannot contain an aligned struct"));
+        }
+        self.expect(Token::Punc('}'))?;
+        Ok(Decl::Struct(decl))
+    }
+
+    // Checks the arguments of "#[packed]" and "#[align(N)]"
+    fn check_layout_attrs(&self, attrs: &[Attribute]) -> Result<()> {
+        if let Some(packed) = find_attr(attrs, "packed") {
+            if !packed.args.is_empty() {
+                return Err(self.tokenizer.error("'packed' takes no arguments"));
+            }
+        }
+        if let Some(align) = find_attr(attrs, "align") {
+            let valid = match align.args.as_slice() {
+                [AttrArg {
+                    key: None,
+                    val: AttrValue::Int(lit),
+                }] => lit.val > 0 && lit.val <= MAX_ALIGN && lit.val & (lit.val - 1) == 0,
+                _ => false,
+            };
+            if !valid {
+                return Err(self
+                    .tokenizer
+                    .error("'align' takes a power of two: e.g. '#[align(64)]'"));
+            }
+            if find_attr(attrs, "packed").is_some() {
+                // C accepts this, but Rust can't express it
+                return Err(self
+                    .tokenizer
+                    .error("a struct cannot be both packed and aligned"));
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the type has a struct with an explicit alignment within it (but
+    // not behind a pointer)
+    fn contains_aligned(&self, t: &Type) -> bool {
+        let name = match t {
+            Type::Array(subtype, _) => return self.contains_aligned(subtype),
+            Type::Base(BaseType::Struct(name)) => name,
+            _ => return false,
+        };
+        match self.symbols.get(name).map(Rc::as_ref) {
+            Some(Decl::Struct(decl)) => {
+                decl.align().is_some() || decl.fields.iter().any(|f| self.contains_aligned(&f.typ))
+            }
+            Some(Decl::Union(decl)) => decl.fields.iter().any(|f| self.contains_aligned(&f.typ)),
+            Some(Decl::Variant(decl)) => decl
+                .cases
+                .iter()
+                .filter_map(|c| c.payload.as_ref())
+                .any(|t| self.contains_aligned(t)),
+            Some(Decl::Alias(decl)) => self.contains_aligned(&decl.typ),
+            Some(Decl::Opaque(decl)) => decl.align.is_some(),
+            _ => false,
+        }
+    }
+
+    // union = "union" ident "{" fields "}"
+    fn parse_union(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect(Token::Union)?;
+        let name = self.expect_decl_name()?;
+        self.expect(Token::Punc('{'))?;
+        let fields = self.parse_fields("field")?;
+        if fields.is_empty() {
+            // Neither C nor Rust can express an empty union
+            return Err(self.tokenizer.error("union must have at least one field"));
+        }
+        if fields.iter().any(|f| f.default.is_some()) {
+            // only one field can be initialized
+            return Err(self.tokenizer.error("union fields can't have defaults"));
+        }
+        if fields.iter().any(|f| f.bits.is_some()) {
+            return Err(self.tokenizer.error("union fields can't have bit ranges"));
+        }
+        if fields.iter().any(|f| f.is_pad()) {
+            return Err(self.tokenizer.error("union fields can't be padding"));
+        }
+        self.expect(Token::Punc('}'))?;
+        Ok(Decl::Union(UnionDecl {
+            prefix,
+            docs,
+            attrs,
+            name,
+            fields,
+        }))
+    }
+
+    // case = ident ("(" type ")")?
+    fn maybe_parse_case(&mut self) -> Result<Option<VariantCase>> {
+        if !matches!(self.tok, Token::Ident(_)) {
+            return Ok(None);
+        }
+        let name = self.expect_ident()?;
+        let payload = if matches!(self.tok, Token::Punc('(')) {
+            self.next_tok()?;
+            let typ = self.parse_type()?;
+            self.expect(Token::Punc(')'))?;
+            Some(typ)
+        } else {
+            None
+        };
+        Ok(Some(VariantCase { name, payload }))
+    }
+
+    // cases = "" | case ("," case)* ","?
+    fn parse_cases(&mut self) -> Result<Vec<VariantCase>> {
+        let mut cases: Vec<VariantCase> = Vec::new();
+        loop {
+            let c = match self.maybe_parse_case()? {
+                Some(c) => c,
+                None => return Ok(cases),
+            };
+            // tag constants are upper-cased on emit, so they must be unique ignoring case
+            if cases.iter().any(|x| x.name.eq_ignore_ascii_case(&c.name)) {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("duplicate variant case '{}'", c.name)));
+            }
+            cases.push(c);
+            if !matches!(self.tok, Token::Punc(',')) {
+                return Ok(cases);
+            }
+            self.next_tok()?;
+        }
+    }
+
+    // variant = "variant" ident inttype? "{" cases "}"
+    fn parse_variant(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect_contextual_keyword("variant")?;
+        let name = self.expect_decl_name()?;
+        let tag = if matches!(self.tok, Token::Punc(':')) {
+            self.parse_inttype()?
+        } else {
+            BaseType::U32
+        };
+        self.expect(Token::Punc('{'))?;
+        let cases = self.parse_cases()?;
+        if cases.is_empty() {
+            return Err(self.tokenizer.error("variant must have at least one case"));
+        }
+        if !int_fits(&tag, cases.len() as i128 - 1) {
+            return Err(self
+                .tokenizer
+                .error("too many variant cases for the tag type"));
+        }
+        self.expect(Token::Punc('}'))?;
+        Ok(Decl::Variant(VariantDecl {
+            prefix,
+            docs,
+            attrs,
+            name,
+            tag,
+            cases,
+        }))
+    }
+
+    // callback = "callback" ident "(" fields ")" ret ";"
+    fn parse_callback(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect_contextual_keyword("callback")?;
+        let name = self.expect_decl_name()?;
+        self.expect(Token::Punc('('))?;
+        let args = self.parse_fields("fn pointer arg")?;
+        self.expect(Token::Punc(')'))?;
+        let (ret, ret_docs) = self.parse_ret()?;
+        self.expect(Token::Punc(';'))?;
+        Ok(Decl::Callback(CallbackDecl {
+            prefix,
+            docs,
+            attrs,
+            name,
+            typ: FnType {
+                args,
+                ret,
+                ret_docs,
+            },
+        }))
+    }
+
+    // alias = "type" ident "=" type ";"
+    fn parse_alias(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect_contextual_keyword("type")?;
+        let name = self.expect_decl_name()?;
+        self.expect(Token::Punc('='))?;
+        let typ = self.parse_type()?;
+        // C needs the types named by a typedef to be declared before it: this
+        // also rules out cycles of aliases
+        if let Some(undeclared) = first_undeclared(&typ, &self.symbols) {
+            return Err(self.tokenizer.error(&format!(
+                "'{}' must be declared before it is aliased",
+                undeclared
+            )));
+        }
+        if type_is_array_recursively(&typ) {
+            // an array typedef would hide array to pointer decay in signatures
+            return Err(self.tokenizer.error("arrays cannot be aliased"));
+        }
+        if typ.any(&|t| matches!(t.nullability(), Some(n) if n != Nullability::Unspecified)) {
+            return Err(self
+                .tokenizer
+                .error("nullability cannot be aliased: annotate the pointers where used"));
+        }
+        self.expect(Token::Punc(';'))?;
+        Ok(Decl::Alias(AliasDecl {
+            prefix,
+            docs,
+            attrs,
+            name,
+            typ,
+        }))
+    }
+
+    // import = "import" string ";"
+    fn parse_import(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect_contextual_keyword("import")?;
+        let path = match &self.tok {
+            Token::Str(s) => s.clone(),
+            _ => {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("expected <string>, found {}", self.tok)))
+            }
+        };
+        if !path.ends_with(".dy") {
+            return Err(self
+                .tokenizer
+                .error(&format!("expected a .dy file, found '{}'", path)));
+        }
+        // the stem names the C header and the Rust module
+        let stem = path
+            .rsplit('/')
+            .next()
+            .unwrap()
+            .strip_suffix(".dy")
+            .unwrap();
+        if !stem.starts_with(is_ident_char_start) || !stem.chars().all(is_ident_char) {
+            return Err(self
+                .tokenizer
+                .error(&format!("import stem '{}' is not a valid identifier", stem)));
+        }
+
+        // parse the imported file: errors are reported within that file
+        let file = self.dir.join(&path);
+        let (canonical, txt) = match (file.canonicalize(), std::fs::read_to_string(&file)) {
+            (Ok(canonical), Ok(txt)) => (canonical, txt),
+            _ => {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("failed to read imported file '{}'", path)))
+            }
+        };
+        if self.importing.contains(&canonical) {
+            return Err(self
+                .tokenizer
+                .error(&format!("import cycle: '{}' is already being parsed", path)));
+        }
+        let mut parser = Parser::new(&txt, Some(&file.to_string_lossy()))?;
+        parser.importing = self.importing.clone();
+        parser.importing.push(canonical);
+        let defn = parser.parse()?;
+
+        self.next_tok()?;
+        self.expect(Token::Punc(';'))?;
+        Ok(Decl::Import(ImportDecl {
+            prefix,
+            docs,
+            attrs,
+            path,
+            defn,
+        }))
+    }
+
+    // opaque = "opaque" ident ("(" opaquelayout ")")? ";"
+    fn parse_opaque(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        mut attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect(Token::Opaque)?;
+        let name = self.expect_decl_name()?;
+        self.resolve_refcounted(&mut attrs)?;
+        let (size, align) = if matches!(self.tok, Token::Punc('(')) {
+            self.next_tok()?;
+            let (size, align) = self.parse_opaque_layout()?;
+            self.expect(Token::Punc(')'))?;
+            (Some(size), align)
+        } else {
+            (None, None)
+        };
+        self.expect(Token::Punc(';'))?;
+        Ok(Decl::Opaque(OpaqueDecl {
+            prefix,
+            docs,
+            attrs,
+            name,
+            size,
+            align,
+        }))
+    }
+
+    // opaquelayout = "size" "=" constexpr ("," "align" "=" intlit)? ","?
+    fn parse_opaque_layout(&mut self) -> Result<(ConstExpr, Option<i128>)> {
+        self.expect_contextual_keyword("size")?;
+        self.expect(Token::Punc('='))?;
+        let size = self.parse_constexpr(&BaseType::U64)?;
+        if size.val == 0 {
+            return Err(self
+                .tokenizer
+                .error("an opaque type's size must be at least 1"));
+        }
+        if matches!(self.tok, Token::Punc(',')) {
+            self.next_tok()?;
+        }
+        if !self.tok_is_contextual_keyword("align") {
+            return Ok((size, None));
+        }
+        self.next_tok()?;
+        self.expect(Token::Punc('='))?;
+        let align = self.parse_intlit(&BaseType::U64)?.val;
+        if align == 0 || align > MAX_ALIGN || align & (align - 1) != 0 {
+            return Err(self
+                .tokenizer
+                .error("an opaque type's align must be a power of two"));
+        }
+        if size.val % align != 0 {
+            // Rust sizes are always a multiple of the alignment
+            return Err(self.tokenizer.error(&format!(
+                "an opaque type's size must be a multiple of its align ({})",
+                align
+            )));
+        }
+        if matches!(self.tok, Token::Punc(',')) {
+            self.next_tok()?;
+        }
+        Ok((size, Some(align)))
+    }
+
+    // Checks that "#[refcounted(retain = f, release = g)]" names both fns. They
+    // are declared later in this file, so their names are always prefixed
+    fn resolve_refcounted(&self, attrs: &mut [Attribute]) -> Result<()> {
+        let Some(attr) = attrs.iter_mut().find(|a| a.name == "refcounted") else {
+            return Ok(());
+        };
+        let key = |arg: &AttrArg| match arg {
+            AttrArg {
+                key: Some(key),
+                val: AttrValue::Ident(_),
+            } => Some(key.clone()),
+            _ => None,
+        };
+        let keys: Vec<_> = attr.args.iter().map(key).collect();
+        if keys.len() != 2
+            || !keys.contains(&Some("retain".to_string()))
+            || !keys.contains(&Some("release".to_string()))
+        {
+            return Err(self.tokenizer.error(
+                "'refcounted' takes the retain and release fns: e.g. '#[refcounted(retain = buf_ref, release = buf_unref)]'",
+            ));
+        }
+        for arg in attr.args.iter_mut() {
+            if let AttrValue::Ident(name) = &mut arg.val {
+                *name = format!("{}{}", self.prefix, name);
+            }
+        }
+        Ok(())
+    }
+
+    // const = "const" ident inttype? "=" (constexpr | string) ";"
+    fn parse_const(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect(Token::Const)?;
+        let name = self.expect_const_name()?;
+        let typ = if matches!(self.tok, Token::Punc(':')) {
+            Some(self.parse_inttype()?)
+        } else {
+            None
+        };
+        self.expect(Token::Punc('='))?;
+        let val = if let Token::Str(s) = &self.tok {
+            if typ.is_some() {
+                return Err(self
+                    .tokenizer
+                    .error("string constants cannot have a declared type"));
+            }
+            let s = s.clone();
+            self.next_tok()?;
+            ConstValue::Str(s)
+        } else {
+            // untyped integer constants are u64
+            let typ = typ.unwrap_or(BaseType::U64);
+            let expr = self.parse_constexpr(&typ)?;
+            ConstValue::Int(typ, expr)
+        };
+        self.expect(Token::Punc(';'))?;
+        Ok(Decl::Const(ConstDecl {
+            prefix,
+            docs,
+            attrs,
+            name,
+            val,
+        }))
+    }
+
+    // enumerator = ident "=" intlit
+    fn maybe_parse_enumerator(&mut self, repr: &BaseType) -> Result<Option<Enumerator>> {
+        if !matches!(self.tok, Token::Ident(_)) {
+            return Ok(None);
+        }
+        let name = self.expect_ident()?;
+        self.expect(Token::Punc('='))?;
+        let val = self.parse_intlit(repr)?;
+        Ok(Some(Enumerator { name, val }))
+    }
+
+    // enumerators = "" | enumerator ("," enumerator)* ","?
+    fn parse_enumerators(&mut self, repr: &BaseType) -> Result<Vec<Enumerator>> {
+        let mut enumerators: Vec<Enumerator> = Vec::new();
+        loop {
+            let e = match self.maybe_parse_enumerator(repr)? {
+                Some(e) => e,
+                None => return Ok(enumerators),
+            };
+            if enumerators.iter().any(|x| x.name == e.name) {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("duplicate enumerator '{}'", e.name)));
+            }
+            enumerators.push(e);
+            if !matches!(self.tok, Token::Punc(',')) {
+                return Ok(enumerators);
+            }
+            self.next_tok()?;
+        }
+    }
+
+    // Follows aliases to the type they name
+    fn resolve_alias(&self, typ: BaseType) -> BaseType {
+        let BaseType::Struct(name) = &typ else {
+            return typ;
+        };
+        match self.symbols.get(name).map(Rc::as_ref) {
+            Some(Decl::Alias(AliasDecl {
+                typ: Type::Base(base),
+                ..
+            })) => self.resolve_alias(base.clone()),
+            _ => typ,
+        }
+    }
+
+    // inttype = ":" basetype
+    //
+    // Aliases of integer types are resolved, so that the bindings use the
+    // fixed-width type the values were checked against
+    fn parse_inttype(&mut self) -> Result<BaseType> {
+        self.expect(Token::Punc(':'))?;
+        let typ = self.parse_basetype()?;
+        let typ = self.resolve_alias(typ);
+        if int_range(&typ).is_none() {
+            return Err(self.tokenizer.error("expected a fixed-width integer type"));
+        }
+        Ok(typ)
+    }
+
+    // enum = "enum" ident inttype "{" enumerators "}"
+    fn parse_enum(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect(Token::Enum)?;
+        let name = self.expect_decl_name()?;
+        let repr = self.parse_inttype()?;
+        self.expect(Token::Punc('{'))?;
+        let enumerators = self.parse_enumerators(&repr)?;
+        self.expect(Token::Punc('}'))?;
+        Ok(Decl::Enum(EnumDecl {
+            prefix,
+            docs,
+            attrs,
+            name,
+            repr,
+            enumerators,
+        }))
+    }
+
+    // errorcode = ident "=" intlit string
+    fn maybe_parse_error_code(&mut self, repr: &BaseType) -> Result<Option<ErrorCode>> {
+        if !matches!(self.tok, Token::Ident(_)) {
+            return Ok(None);
+        }
+        let name = self.expect_ident()?;
+        self.expect(Token::Punc('='))?;
+        let val = self.parse_intlit(repr)?;
+        let msg = match &self.tok {
+            Token::Str(s) => s.clone(),
+            _ => {
+                return Err(self.tokenizer.error(&format!(
+                    "expected <string> message for '{}', found {}",
+                    name, self.tok
+                )))
+            }
+        };
+        self.next_tok()?;
+        Ok(Some(ErrorCode { name, val, msg }))
+    }
+
+    // errorcodes = "" | errorcode ("," errorcode)* ","?
+    fn parse_error_codes(&mut self, repr: &BaseType) -> Result<Vec<ErrorCode>> {
+        let mut errors: Vec<ErrorCode> = Vec::new();
+        loop {
+            let e = match self.maybe_parse_error_code(repr)? {
+                Some(e) => e,
+                None => return Ok(errors),
+            };
+            if errors.iter().any(|x| x.name == e.name) {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("duplicate error code '{}'", e.name)));
+            }
+            // each status must map to one message
+            if errors.iter().any(|x| x.val.val == e.val.val) {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("duplicate error code value {}", e.val.text)));
+            }
+            errors.push(e);
+            if !matches!(self.tok, Token::Punc(',')) {
+                return Ok(errors);
+            }
+            self.next_tok()?;
+        }
+    }
+
+    // errors = "errors" ident inttype "{" errorcodes "}"
+    fn parse_errors(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect_contextual_keyword("errors")?;
+        let name = self.expect_decl_name()?;
+        let repr = self.parse_inttype()?;
+        self.expect(Token::Punc('{'))?;
+        let errors = self.parse_error_codes(&repr)?;
+        if !errors.iter().any(|e| e.val.val == 0) {
+            return Err(self
+                .tokenizer
+                .error("errors must have a success code with the value 0"));
+        }
+        self.expect(Token::Punc('}'))?;
+        Ok(Decl::Errors(ErrorsDecl {
+            prefix,
+            docs,
+            attrs,
+            name,
+            repr,
+            errors,
+        }))
+    }
+
+    // flags = "flags" ident inttype "{" enumerators "}"
+    fn parse_flags(
+        &mut self,
+        prefix: Skip,
+        docs: Vec<String>,
+        attrs: Vec<Attribute>,
+    ) -> Result<Decl> {
+        self.expect_contextual_keyword("flags")?;
+        let name = self.expect_decl_name()?;
+        let repr = self.parse_inttype()?;
+        self.expect(Token::Punc('{'))?;
+        let flags = self.parse_enumerators(&repr)?;
+        self.expect(Token::Punc('}'))?;
+        Ok(Decl::Flags(FlagsDecl {
+            prefix,
+            docs,
+            attrs,
+            name,
+            repr,
+            flags,
+        }))
+    }
+
+    // decl = docs attrs (func | struct | union | variant | opaque | const | enum | flags | callback | alias | errors | import)
+    fn maybe_parse_decl(&mut self) -> Result<Option<Decl>> {
+        let (prefix, docs, attrs) = self.parse_docs_and_attrs()?;
+        let decl = if self.tok_is_contextual_keyword("flags") {
+            self.parse_flags(prefix, docs, attrs)?
+        } else if self.tok_is_contextual_keyword("variant") {
+            self.parse_variant(prefix, docs, attrs)?
+        } else if self.tok_is_contextual_keyword("callback") {
+            self.parse_callback(prefix, docs, attrs)?
+        } else if self.tok_is_contextual_keyword("type") {
+            self.parse_alias(prefix, docs, attrs)?
+        } else if self.tok_is_contextual_keyword("errors") {
+            self.parse_errors(prefix, docs, attrs)?
+        } else if self.tok_is_contextual_keyword("import") {
+            self.parse_import(prefix, docs, attrs)?
+        } else if self.tok_is_contextual_keyword("library") {
+            return Err(self
+                .tokenizer
+                .error("the library header must come before any declarations"));
+        } else if self.tok_is_contextual_keyword("prefix") {
+            return Err(self
+                .tokenizer
+                .error("the prefix must come before any declarations"));
+        } else {
+            match self.tok {
+                Token::Fn => self.parse_fn(prefix, docs, attrs)?,
+                Token::Struct => self.parse_struct(prefix, docs, attrs)?,
+                Token::Union => self.parse_union(prefix, docs, attrs)?,
+                Token::Opaque => self.parse_opaque(prefix, docs, attrs)?,
+                Token::Const => self.parse_const(prefix, docs, attrs)?,
+                Token::Enum => self.parse_enum(prefix, docs, attrs)?,
+                _ if !attrs.is_empty() => {
+                    return Err(self.tokenizer.error(&format!(
+                        "expected a declaration after attributes, found {}",
+                        self.tok
+                    )))
+                }
+                _ => return Ok(None),
+            }
+        };
+        self.check_attr_sites(decl.attrs(), decl_kind(&decl))?;
+        Ok(Some(decl))
+    }
+
+    // library = "library" ident "{" (libkey "=" string ("," libkey "=" string)* ","?)? "}"
+    // libkey = "version" | "soname" | "link"
+    fn maybe_parse_library(&mut self) -> Result<Option<Library>> {
+        if !self.tok_is_contextual_keyword("library") {
+            return Ok(None);
+        }
+        let prefix = self.skip.clone();
+        self.next_tok()?;
+        let mut library = Library {
+            prefix,
+            name: self.expect_ident()?,
+            version: None,
+            soname: None,
+            link: None,
+        };
+        self.expect(Token::Punc('{'))?;
+        while matches!(self.tok, Token::Ident(_)) {
+            let key = self.expect_ident()?;
+            let field = match key.as_str() {
+                "version" => &mut library.version,
+                "soname" => &mut library.soname,
+                "link" => &mut library.link,
+                _ => {
+                    return Err(self
+                        .tokenizer
+                        .error(&format!("unknown library key '{}'", key)))
+                }
+            };
+            if field.is_some() {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("duplicate library key '{}'", key)));
+            }
+            self.expect(Token::Punc('='))?;
+            match &self.tok {
+                Token::Str(s) => *field = Some(s.clone()),
+                _ => {
+                    return Err(self
+                        .tokenizer
+                        .error(&format!("expected <string>, found {}", self.tok)))
+                }
+            }
+            self.next_tok()?;
+            if !matches!(self.tok, Token::Punc(',')) {
+                break;
+            }
+            self.next_tok()?;
+        }
+        self.expect(Token::Punc('}'))?;
+        // the header isn't emitted, so neither are the blank lines after it
+        self.skip = self.skip.trim_start();
+        Ok(Some(library))
+    }
+
+    // symbolprefix = "prefix" ident ";"
+    fn maybe_parse_symbol_prefix(&mut self) -> Result<Option<SymbolPrefix>> {
+        if !self.tok_is_contextual_keyword("prefix") {
+            return Ok(None);
+        }
+        let prefix = self.skip.clone();
+        self.next_tok()?;
+        let name = self.expect_ident()?;
+        if name.trim_end_matches('_').is_empty() {
+            return Err(self.tokenizer.error(&format!("invalid prefix '{}'", name)));
+        }
+        self.expect(Token::Punc(';'))?;
+        self.skip = self.skip.trim_start();
+        self.prefix = name.clone();
+        Ok(Some(SymbolPrefix { prefix, name }))
+    }
+
+    // All versions of a fn construct or release the same type, and a type
+    // has a single destructor
+    fn check_ownership_versions(&self, func: &FuncDecl, apis: &Apis) -> Result<()> {
+        if let Some(Decl::Fn(prev)) = apis.latest(&func.name) {
+            if prev.constructs() != func.constructs() || prev.destructs() != func.destructs() {
+                return Err(self.tokenizer.error(&format!(
+                    "all versions of '{}' must have the same 'constructor' and 'destructor' attributes",
+                    func.name
+                )));
+            }
+        }
+        let Some(released) = func.destructs() else {
+            return Ok(());
+        };
+        for decl in self.symbols.values() {
+            match decl.as_ref() {
+                Decl::Fn(other)
+                    if other.name != func.name && other.destructs() == Some(released) =>
+                {
+                    return Err(self.tokenizer.error(&format!(
+                        "'{}' already has a destructor: '{}'",
+                        released, other.name
+                    )))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    // Records the C macros of a decl, which must not already be defined
+    fn add_macros(&self, macros: &mut HashMap<String, String>, decl: &Decl) -> Result<()> {
+        for name in decl.macro_names() {
+            if let Some(other) = macros.get(&name) {
+                return Err(self.tokenizer.error(&format!(
+                    "duplicate C macro '{}' (also defined by '{}')",
+                    name, other
+                )));
+            }
+            macros.insert(name, decl.name());
+        }
+        Ok(())
+    }
+
+    // Objects are only owned if they can be released
+    fn check_constructors(&self, decls: &[Rc<Decl>]) -> Result<()> {
+        for decl in decls {
+            let Decl::Fn(func) = decl.as_ref() else {
+                continue;
+            };
+            let Some(constructed) = func.constructs() else {
+                continue;
+            };
+            let has_destructor = self.symbols.values().any(
+                |d| matches!(d.as_ref(), Decl::Fn(other) if other.destructs() == Some(constructed)),
+            );
+            let refcounted = matches!(
+                self.symbols.get(constructed).map(Rc::as_ref),
+                Some(Decl::Opaque(opaque)) if opaque.release().is_some()
+            );
+            if !has_destructor && !refcounted {
+                return Err(self.tokenizer.error(&format!(
+                    "constructor '{}' needs a '#[destructor({})]' fn, or a refcounted type",
+                    func.name, constructed
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // The retain and release fns of a refcounted type are declared in the same
+    // file, and take the object. A retain may also return it
+    fn check_refcounted(&self, decls: &[Rc<Decl>], apis: &Apis) -> Result<()> {
+        for decl in decls {
+            let Decl::Opaque(opaque) = decl.as_ref() else {
+                continue;
+            };
+            let (Some(retain), Some(release)) = (opaque.retain(), opaque.release()) else {
+                continue;
+            };
+            let counted = Type::Base(BaseType::Struct(opaque.name.clone()));
+            for (name, retains) in [(retain, true), (release, false)] {
+                let Some(idx) = apis.name_to_api_idx.get(name) else {
+                    return Err(self.tokenizer.error(&format!(
+                        "refcounted '{}' names '{}', which is not a fn in this file",
+                        opaque.name, name
+                    )));
+                };
+                for version in apis.apis[*idx].all_versions.values() {
+                    let Decl::Fn(func) = version.as_ref() else {
+                        continue;
+                    };
+                    let takes = matches!(func.args.as_slice(),
+                        [arg] if matches!(&arg.typ, Type::Pointer(Mutability::Mut, _, pointee) if **pointee == counted));
+                    let returns = match &func.ret {
+                        ReturnType::None => true,
+                        ReturnType::Some(Type::Pointer(Mutability::Mut, _, pointee)) => {
+                            retains && **pointee == counted
+                        }
+                        _ => false,
+                    };
+                    if !takes || !returns {
+                        return Err(self.tokenizer.error(&format!(
+                            "'{}_v{}' must take a single '*mut {}' arg, and return nothing{}",
+                            func.name,
+                            func.version.0,
+                            opaque.name,
+                            if retains { " or the object" } else { "" }
+                        )));
+                    }
+                }
+            }
+            let destructor = self.symbols.values().find_map(|d| match d.as_ref() {
+                Decl::Fn(func) if func.destructs() == Some(opaque.name.as_str()) => Some(func),
+                _ => None,
+            });
+            if let Some(destructor) = destructor {
+                return Err(self.tokenizer.error(&format!(
+                    "refcounted '{}' is released with '{}', so it can't have a destructor: '{}'",
+                    opaque.name, release, destructor.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(&mut self) -> Result<ApiDefn> {
+        let mut apis = Apis::new();
+        let mut decls = vec![];
+
+        // the library header and symbol prefix come first, if there are any
+        let library = self.maybe_parse_library()?;
+        let symbol_prefix = self.maybe_parse_symbol_prefix()?;
+
+        // C macros share one namespace, so names that only differ before
+        // prefixing or upper-casing can still collide
+        let mut macros: HashMap<String, String> = HashMap::new();
+        if let Some(lib) = &library {
+            for name in lib.macro_names() {
+                macros.insert(name, format!("library {}", lib.name));
+            }
+        }
+
+        while let Some(decl) = self.maybe_parse_decl()? {
+            let decl = Rc::new(decl);
+
+            // add the symbols of the imported file (but not its own imports)
+            if let Decl::Import(import) = decl.as_ref() {
+                for imported in &import.defn.decls {
+                    if matches!(imported.as_ref(), Decl::Import(_)) {
+                        continue;
+                    }
+                    let name = imported.name();
+                    if self.symbols.contains_key(&name) {
+                        return Err(self.tokenizer.error(&format!(
+                            "duplicate symbol '{}' imported from '{}'",
+                            name, import.path
+                        )));
+                    }
+                    self.add_macros(&mut macros, imported)?;
+                    self.symbols.insert(name, imported.clone());
+                }
+                decls.push(decl);
+                continue;
+            }
+
+            let name = decl.name();
+
+            // add the symbol
+            if self.symbols.contains_key(&name) {
+                return Err(self
+                    .tokenizer
+                    .error(&format!("duplicate symbol '{}'", name)));
+            }
+            self.add_macros(&mut macros, &decl)?;
+            self.symbols.insert(name, decl.clone());
+
+            // add the api
+            if let Decl::Fn(func) = decl.as_ref() {
+                self.check_ownership_versions(func, &apis)?;
+                if apis
+                    .insert(func.name.to_string(), func.version, decl.clone())
+                    .is_some()
+                {
                     return Err(self.tokenizer.error(&format!(
                         "duplicate version {} for symbol '{}'",
                         func.version.0, func.name
@@ -568,9 +2296,13 @@ impl Parser {
         }
         let suffix = self.skip.clone();
         self.expect(Token::EndOfFile)?;
+        self.check_constructors(&decls)?;
+        self.check_refcounted(&decls, &apis)?;
 
         Ok(ApiDefn {
-            symbols,
+            library,
+            symbol_prefix,
+            symbols: std::mem::take(&mut self.symbols),
             apis,
             decls,
             suffix,
@@ -578,6 +2310,57 @@ impl Parser {
     }
 }
 
+// Removes the doc comments that directly precede the next token from the skip
+fn take_docs(skip: &mut Skip) -> Vec<String> {
+    let mut docs = vec![];
+    let mut keep = skip.0.len();
+    for (i, elem) in skip.0.iter().enumerate().rev() {
+        match elem {
+            SkipElem::Whitespace(_) => continue,
+            SkipElem::DocComment(lines) => {
+                docs.splice(0..0, lines.iter().cloned());
+                keep = i;
+            }
+            _ => break,
+        }
+    }
+    skip.0.truncate(keep);
+    docs
+}
+
+// What a decl is called in error messages
+fn decl_kind(decl: &Decl) -> &'static str {
+    match decl {
+        Decl::Fn(_) => "fn",
+        Decl::Struct(_) => "struct",
+        Decl::Opaque(_) => "opaque",
+        Decl::Const(_) => "const",
+        Decl::Enum(_) => "enum",
+        Decl::Flags(_) => "flags",
+        Decl::Union(_) => "union",
+        Decl::Variant(_) => "variant",
+        Decl::Callback(_) => "callback",
+        Decl::Alias(_) => "type",
+        Decl::Errors(_) => "errors",
+        Decl::Import(_) => "import",
+    }
+}
+
+// Returns the first named type within the type that isn't a known symbol
+fn first_undeclared(t: &Type, symbols: &HashMap<String, Rc<Decl>>) -> Option<String> {
+    match t {
+        Type::Pointer(_, _, subtype) | Type::Array(subtype, _) => {
+            first_undeclared(subtype, symbols)
+        }
+        Type::FnPtr(fntype) => fntype
+            .types()
+            .into_iter()
+            .find_map(|t| first_undeclared(t, symbols)),
+        Type::Base(BaseType::Struct(name)) if !symbols.contains_key(name) => Some(name.clone()),
+        Type::Base(_) => None,
+    }
+}
+
 // Retricts allowed types such that they can be sanely representable in C
 fn type_is_sane_for_c(t: &Type) -> bool {
     match t {
@@ -586,25 +2369,93 @@ fn type_is_sane_for_c(t: &Type) -> bool {
             // multi-dim array support in C is sad, so just don't do it
             !type_is_array_recursively(subtype)
         }
-        Type::Pointer(subtype) => {
+        Type::Pointer(_, _, subtype) => {
             // Pointers to arrays are wierd in C because of array to
             // pointer decay. Even in type signatures where the syntax
             // is allowed, it's actually just a pointer. C doesn't actually
             // have a semantic concept of pointers to arrays
             !type_is_array_recursively(subtype)
         }
-        Type::Base(_) => true, // always sane
+        Type::FnPtr(_) => true, // args and return already checked
+        Type::Base(_) => true,  // always sane
     }
 }
 
 fn type_is_array_recursively(t: &Type) -> bool {
     match t {
         Type::Array(_, _) => true,
-        Type::Pointer(subtype) => type_is_array_recursively(subtype),
+        Type::Pointer(_, _, subtype) => type_is_array_recursively(subtype),
+        Type::FnPtr(_) => false,
         Type::Base(_) => false,
     }
 }
 
+// Attributes understood by dandiya itself, and what they may be attached to:
+// any others must be namespaced
+const ATTRIBUTES: &[(&str, &[&str])] = &[
+    ("packed", &["struct"]),
+    ("align", &["struct"]),
+    ("pad", &["field"]),
+    ("len_of", &["arg"]),
+    ("in", &["arg", "fn pointer arg"]),
+    ("out", &["arg", "fn pointer arg"]),
+    ("inout", &["arg", "fn pointer arg"]),
+    ("returns_status", &["fn"]),
+    ("constructor", &["fn"]),
+    ("destructor", &["fn"]),
+    ("refcounted", &["opaque"]),
+];
+
+// Largest alignment that Rust's "#[repr(align(N))]" accepts
+const MAX_ALIGN: i128 = 1 << 29;
+
+// Binary operators from lowest to highest precedence: for these operators,
+// this order is the same in both C and Rust
+const BINOP_LEVELS: &[&[BinOp]] = &[
+    &[BinOp::Or],
+    &[BinOp::And],
+    &[BinOp::Shl],
+    &[BinOp::Add, BinOp::Sub],
+    &[BinOp::Mul, BinOp::Div],
+];
+
+// Returns None for anything that would overflow or is undefined in C
+fn eval_binop(op: BinOp, lhs: i128, rhs: i128) -> Option<i128> {
+    match op {
+        BinOp::Add => lhs.checked_add(rhs),
+        BinOp::Sub => lhs.checked_sub(rhs),
+        BinOp::Mul => lhs.checked_mul(rhs),
+        BinOp::Div => lhs.checked_div(rhs),
+        BinOp::Shl if lhs < 0 || !(0..64).contains(&rhs) => None,
+        BinOp::Shl => lhs.checked_mul(1 << rhs),
+        BinOp::And => Some(lhs & rhs),
+        BinOp::Or => Some(lhs | rhs),
+    }
+}
+
+// Returns the (min, max) representable values of a fixed-width integer type
+fn int_range(b: &BaseType) -> Option<(i128, i128)> {
+    let range = match b {
+        BaseType::U8 => (0, u8::MAX as i128),
+        BaseType::I8 => (i8::MIN as i128, i8::MAX as i128),
+        BaseType::U16 => (0, u16::MAX as i128),
+        BaseType::I16 => (i16::MIN as i128, i16::MAX as i128),
+        BaseType::U32 => (0, u32::MAX as i128),
+        BaseType::I32 => (i32::MIN as i128, i32::MAX as i128),
+        BaseType::U64 => (0, u64::MAX as i128),
+        BaseType::I64 => (i64::MIN as i128, i64::MAX as i128),
+        _ => return None, // not an integer, or platform-dependent width
+    };
+    Some(range)
+}
+
+fn int_fits(b: &BaseType, val: i128) -> bool {
+    match int_range(b) {
+        Some((min, max)) => min <= val && val <= max,
+        None => false,
+    }
+}
+
 pub fn parse(inp: &str, srcname: Option<&str>) -> Result<ApiDefn> {
     Parser::new(inp, srcname)?.parse()
 }
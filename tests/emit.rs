@@ -10,11 +10,11 @@ fn check(src: &str, emit_c: &str, emit_rust: &str) {
     };
 
     let c = emit(&api, Language::C, options.clone());
-    let expected_c = format!("{}{}{}", c::PREAMBLE, emit_c, c::POSTAMBLE);
+    let expected_c = format!("{}{}{}", c::preamble(&api), emit_c, c::POSTAMBLE);
     assert_eq!(c, expected_c);
 
     let rust = emit(&api, Language::Rust, options.clone());
-    let expected_rust = format!("{}{}{}", rust::PREAMBLE, emit_rust, rust::POSTAMBLE);
+    let expected_rust = format!("{}{}{}", rust::preamble(&api), emit_rust, rust::POSTAMBLE);
     assert_eq!(rust, expected_rust);
 }
 
@@ -23,7 +23,7 @@ fn emit_simple_func() {
     let src = "fn(v1) my_func(a: u8, b: u16) -> u64;";
     let c = "DANDIYA_API_EXPORT uint64_t my_func_v1(uint8_t a, uint16_t b);";
     let rust = "extern \"C\" { pub fn my_func_v1(a: u8, b: u16) -> u64; }";
-    check(src, &c, rust);
+    check(src, c, rust);
 }
 
 #[test]
@@ -51,7 +51,158 @@ pub struct name {
   pub baz: [*mut *mut u8; 8],
 }";
 
-    check(src, &c, rust);
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_union() {
+    let src = "\
+union name {
+  foo: *u64,
+  bar: [u16; 4],
+  baz: data,
+  qux: [data; 2],
+}";
+
+    let c = "\
+typedef union name name_t;
+union name {
+  uint64_t* foo;
+  uint16_t bar[4];
+  data_t baz;
+  data_t qux[2];
+};";
+
+    let rust = "\
+#[repr(C)]
+pub union name {
+  pub foo: *mut u64,
+  pub bar: [u16; 4],
+  pub baz: core::mem::ManuallyDrop<data>,
+  pub qux: core::mem::ManuallyDrop<[data; 2]>,
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_variant() {
+    let src = "variant event: u8 { Packet(packet), Error(u32), Idle }";
+
+    let c = "\
+typedef struct event event_t;
+#define EVENT_PACKET ((uint8_t)(0))
+#define EVENT_ERROR ((uint8_t)(1))
+#define EVENT_IDLE ((uint8_t)(2))
+struct event {
+  uint8_t tag;
+  union {
+    packet_t Packet;
+    uint32_t Error;
+  } data;
+};";
+
+    let rust = "\
+#[repr(C)]
+#[allow(non_snake_case)]
+pub union event_data {
+  pub Packet: core::mem::ManuallyDrop<packet>,
+  pub Error: u32,
+}
+#[repr(C)]
+pub struct event {
+  pub tag: u8,
+  pub data: event_data,
+}
+impl event {
+  pub const PACKET: u8 = 0;
+  pub const ERROR: u8 = 1;
+  pub const IDLE: u8 = 2;
+}
+pub enum event_enum {
+  Packet(packet),
+  Error(u32),
+  Idle,
+}
+impl core::convert::TryFrom<event> for event_enum {
+  type Error = u8;
+  fn try_from(raw: event) -> core::result::Result<event_enum, u8> {
+    match raw.tag {
+      event::PACKET => Ok(event_enum::Packet(core::mem::ManuallyDrop::into_inner(unsafe { raw.data.Packet }))),
+      event::ERROR => Ok(event_enum::Error(unsafe { raw.data.Error })),
+      event::IDLE => Ok(event_enum::Idle),
+      tag => Err(tag),
+    }
+  }
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_variant_without_payloads() {
+    let src = "variant state { On, Off }";
+
+    let c = "\
+typedef struct state state_t;
+#define STATE_ON ((uint32_t)(0))
+#define STATE_OFF ((uint32_t)(1))
+struct state {
+  uint32_t tag;
+};";
+
+    let rust = "\
+#[repr(C)]
+pub struct state {
+  pub tag: u32,
+}
+impl state {
+  pub const ON: u32 = 0;
+  pub const OFF: u32 = 1;
+}
+pub enum state_enum {
+  On,
+  Off,
+}
+impl core::convert::TryFrom<state> for state_enum {
+  type Error = u32;
+  fn try_from(raw: state) -> core::result::Result<state_enum, u32> {
+    match raw.tag {
+      state::ON => Ok(state_enum::On),
+      state::OFF => Ok(state_enum::Off),
+      tag => Err(tag),
+    }
+  }
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_union_copy_fields() {
+    let src = "\
+enum color: u8 { RED = 0, GREEN = 1 }
+type handle = u32;
+struct data { x: u32 }
+union value { c: color, h: handle, d: data }
+variant ev: u8 { Color(color), Data(data) }";
+
+    let api = parse(src, None).unwrap();
+    let rust = emit(&api, Language::Rust, Options::default());
+
+    let value = "\
+pub union value {
+  pub c: color,
+  pub h: handle,
+  pub d: core::mem::ManuallyDrop<data>,
+}";
+    assert!(rust.contains(value));
+
+    let ev = "\
+  pub Color: color,
+  pub Data: core::mem::ManuallyDrop<data>,";
+    assert!(rust.contains(ev));
+    assert!(rust.contains("ev::COLOR => Ok(ev_enum::Color(unsafe { raw.data.Color })),"));
 }
 
 #[test]
@@ -85,7 +236,7 @@ pub struct data {
 extern \"C\" { pub fn do_thing_v1(dat: *mut data) -> u8; }
 extern \"C\" { pub fn do_thing_v2(dat: *mut data, p: u16) -> *mut u8; }";
 
-    check(src, &c, rust);
+    check(src, c, rust);
 }
 
 #[test]
@@ -94,7 +245,621 @@ fn emit_opaque() {
     let c = "typedef struct mytype mytype_t;";
     let rust = "#[repr(C)]\npub struct mytype {_opaque_data: [u8; 0]}";
 
-    check(src, &c, rust);
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_fn_ptrs() {
+    let src = "\
+callback cb(c: *ctx, n: u32) -> i32;
+struct s {
+  f: fn(c: *ctx, n: u32) -> i32,
+  arr: [fn(); 4],
+  ptr: *fn() -> *u8,
+  named: cb,
+  ret: fn() -> fn(x: u8) -> u16,
+}
+fn(v1) get(c: *ctx, f: fn()) -> fn(a: u8);";
+
+    let c = "\
+typedef int32_t (*cb_t)(ctx_t* c, uint32_t n);
+typedef struct s s_t;
+struct s {
+  int32_t (*f)(ctx_t* c, uint32_t n);
+  void (*arr[4])(void);
+  uint8_t* (**ptr)(void);
+  cb_t named;
+  uint16_t (*(*ret)(void))(uint8_t x);
+};
+DANDIYA_API_EXPORT void (*get_v1(ctx_t* c, void (*f)(void)))(uint8_t a);";
+
+    let rust = "\
+pub type cb = Option<unsafe extern \"C\" fn(c: *mut ctx, n: u32) -> i32>;
+#[repr(C)]
+pub struct s {
+  pub f: Option<unsafe extern \"C\" fn(c: *mut ctx, n: u32) -> i32>,
+  pub arr: [Option<unsafe extern \"C\" fn()>; 4],
+  pub ptr: *mut Option<unsafe extern \"C\" fn() -> *mut u8>,
+  pub named: cb,
+  pub ret: Option<unsafe extern \"C\" fn() -> Option<unsafe extern \"C\" fn(x: u8) -> u16>>,
+}
+extern \"C\" { pub fn get_v1(c: *mut ctx, f: Option<unsafe extern \"C\" fn()>) -> Option<unsafe extern \"C\" fn(a: u8)>; }";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_const_pointers() {
+    let src = "\
+struct s {
+  a: *const u8,
+  b: *mut u8,
+  c: *u8,
+  d: *const *mut s,
+  e: *mut *const s,
+  f: *const fn(p: *const u8),
+}
+fn(v1) copy(dst: *mut u8, src: *const u8) -> *const u8;";
+
+    let c = "\
+typedef struct s s_t;
+struct s {
+  const uint8_t* a;
+  uint8_t* b;
+  uint8_t* c;
+  s_t* const* d;
+  const s_t** e;
+  void (* const* f)(const uint8_t* p);
+};
+DANDIYA_API_EXPORT const uint8_t* copy_v1(uint8_t* dst, const uint8_t* src);";
+
+    let rust = "\
+#[repr(C)]
+pub struct s {
+  pub a: *const u8,
+  pub b: *mut u8,
+  pub c: *mut u8,
+  pub d: *const *mut s,
+  pub e: *mut *const s,
+  pub f: *const Option<unsafe extern \"C\" fn(p: *const u8)>,
+}
+extern \"C\" { pub fn copy_v1(dst: *mut u8, src: *const u8) -> *const u8; }";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_nullability() {
+    let src = "\
+struct s {
+  a: *u8!,
+  b: *?const u8,
+  c: **?u8!,
+}
+fn(v1) find(c: *s!, hint: *?u8, n: u32) -> *const u8!;";
+
+    let c = "\
+typedef struct s s_t;
+struct s {
+  uint8_t* DANDIYA_NONNULL a;
+  const uint8_t* DANDIYA_NULLABLE b;
+  uint8_t* DANDIYA_NULLABLE* DANDIYA_NONNULL c;
+};
+DANDIYA_API_EXPORT __attribute__((nonnull(1), returns_nonnull)) const uint8_t* DANDIYA_NONNULL find_v1(s_t* DANDIYA_NONNULL c, uint8_t* DANDIYA_NULLABLE hint, uint32_t n);";
+
+    let rust = "\
+#[repr(C)]
+pub struct s {
+  pub a: *mut u8,
+  pub b: *const u8,
+  pub c: *mut *mut u8,
+}
+extern \"C\" { pub fn find_v1(c: *mut s, hint: *mut u8, n: u32) -> *const u8; }";
+
+    let api = parse(src, None).unwrap();
+    let options = Options {
+        api_forward_to_latest: false,
+        ..Default::default()
+    };
+    let out = emit(&api, Language::C, options.clone());
+    assert!(out.contains("#    define DANDIYA_NONNULL _Nonnull\n"));
+    assert!(out.ends_with(&format!("{}{}", c, c::POSTAMBLE)));
+    let out = emit(&api, Language::Rust, options.clone());
+    assert_eq!(
+        out,
+        format!("{}{}{}", rust::preamble(&api), rust, rust::POSTAMBLE)
+    );
+}
+
+#[test]
+fn emit_nullability_wrappers() {
+    let src = "\
+opaque ctx;
+fn(v1) find(c: *ctx!, hint: *?u8, n: u32) -> *?const u8;";
+    let rust = emit(
+        &parse(src, None).unwrap(),
+        Language::Rust,
+        Options::default(),
+    );
+    let wrapper = "\
+pub unsafe fn find(c: core::ptr::NonNull<ctx>, hint: Option<core::ptr::NonNull<u8>>, n: u32) -> Option<core::ptr::NonNull<u8>> { \
+core::ptr::NonNull::new(find_v1(c.as_ptr(), hint.map_or(core::ptr::null_mut(), |p| p.as_ptr()), n) as *mut _) }\n";
+    assert!(rust.contains(wrapper));
+
+    let c = emit(&parse(src, None).unwrap(), Language::C, Options::default());
+    let wrapper = "\
+static inline __attribute__((nonnull(1))) const uint8_t* DANDIYA_NULLABLE find(ctx_t* DANDIYA_NONNULL c, uint8_t* DANDIYA_NULLABLE hint, uint32_t n) { \
+return find_v1(c, hint, n); }\n";
+    assert!(c.contains(wrapper));
+}
+
+#[test]
+fn emit_nullability_completeness() {
+    // once any pointer is annotated, clang expects every pointer to be
+    let src = "\
+callback cb(a: *u8);
+struct s {
+  p: *u8,
+  f: fn(x: **u8),
+  flags: u32 { ready: 1 },
+}
+errors err: i32 { OK = 0 \"ok\" }
+fn(v1) find(c: *s!, hint: *u8) -> *?const u8;";
+
+    let c = "\
+typedef void (* DANDIYA_NULL_UNSPECIFIED cb_t)(uint8_t* DANDIYA_NULL_UNSPECIFIED a);
+typedef struct s s_t;
+struct s {
+  uint8_t* DANDIYA_NULL_UNSPECIFIED p;
+  void (* DANDIYA_NULL_UNSPECIFIED f)(uint8_t* DANDIYA_NULL_UNSPECIFIED* DANDIYA_NULL_UNSPECIFIED x);
+  uint32_t flags;
+};
+static inline uint32_t s_flags_ready(const s_t* DANDIYA_NONNULL s) { return (uint32_t)((s->flags >> 0) & 0x1U); }
+static inline void s_set_flags_ready(s_t* DANDIYA_NONNULL s, uint32_t v) { \
+s->flags = (uint32_t)((s->flags & ~(0x1U << 0)) | ((v & 0x1U) << 0)); }
+typedef int32_t err_t;
+#define ERR_OK ((err_t)(0))
+static inline const char* DANDIYA_NONNULL err_strerror(err_t err) {
+  switch (err) {
+    case ERR_OK: return \"ok\";
+    default: return \"unknown error\";
+  }
+}
+DANDIYA_API_EXPORT __attribute__((nonnull(1))) const uint8_t* DANDIYA_NULLABLE \
+find_v1(s_t* DANDIYA_NONNULL c, uint8_t* DANDIYA_NULL_UNSPECIFIED hint);";
+
+    let api = parse(src, None).unwrap();
+    let options = Options {
+        api_forward_to_latest: false,
+        ..Default::default()
+    };
+    let out = emit(&api, Language::C, options);
+    assert!(out.contains("#    define DANDIYA_NULL_UNSPECIFIED _Null_unspecified\n"));
+    assert!(out.ends_with(&format!("{}{}", c, c::POSTAMBLE)));
+
+    // without any annotations the header is unchanged
+    let api = parse("fn(v1) get(p: *u8) -> *u8;", None).unwrap();
+    let out = emit(&api, Language::C, Options::default());
+    assert!(!out.contains("DANDIYA_NULL_UNSPECIFIED"));
+    assert!(out.contains("DANDIYA_API_EXPORT uint8_t* get_v1(uint8_t* p);"));
+}
+
+#[test]
+fn emit_len_of() {
+    let src = "\
+struct pkt { n: u8 }
+fn(v1) send(
+  /// Bytes to send
+  buf: *const u8,
+  /// Length of buf
+  #[len_of(buf)] n: u64,
+) -> i32;
+fn(v1) recv(#[len_of(pkts)] n: usize, pkts: *?pkt);";
+    let api = parse(src, None).unwrap();
+
+    // the C signature doesn't change
+    let c = emit(&api, Language::C, Options::default());
+    assert!(c.contains(
+        "DANDIYA_API_EXPORT int32_t send_v1(const uint8_t* DANDIYA_NULL_UNSPECIFIED buf, uint64_t n);"
+    ));
+
+    // but the Rust wrappers take slices
+    let rust = emit(&api, Language::Rust, Options::default());
+    let wrappers = "\
+/// # Arguments
+///
+/// * `buf` - Bytes to send
+pub unsafe fn send(buf: &[u8]) -> i32 { let n = buf.len() as u64; send_v1(buf.as_ptr(), n) }
+pub unsafe fn recv(pkts: Option<&mut [pkt]>) { let n = pkts.as_deref().map_or(0, |s| s.len()); \
+recv_v1(n, pkts.map_or(core::ptr::null_mut(), |s| s.as_mut_ptr())) }
+";
+    assert!(rust.ends_with(&format!("{}{}", wrappers, rust::POSTAMBLE)));
+}
+
+#[test]
+fn emit_directions() {
+    let src = "\
+opaque ctx;
+struct batch { n: u32 }
+fn(v1) fetch(c: *ctx, #[out] b: *batch) -> u64;
+fn(v1) two(#[out] a: *u32, #[out] b: *batch);
+fn(v1) upd(
+  /// The batch to update
+  #[inout] b: *batch,
+  #[inout] n: *?u32,
+);";
+    let api = parse(src, None).unwrap();
+
+    // the C header documents the directions
+    let c = emit(&api, Language::C, Options::default());
+    let upd = "\
+/**
+ * @param[in,out] b The batch to update
+ * @param[in,out] n
+ */
+DANDIYA_API_EXPORT void upd_v1(batch_t* DANDIYA_NULL_UNSPECIFIED b, uint32_t* DANDIYA_NULLABLE n);";
+    assert!(c.contains(
+        "/** @param[out] b */\nDANDIYA_API_EXPORT uint64_t fetch_v1(\
+ctx_t* DANDIYA_NULL_UNSPECIFIED c, batch_t* DANDIYA_NULL_UNSPECIFIED b);"
+    ));
+    assert!(c.contains(upd));
+
+    // the Rust wrappers return out args, and take inout args by reference
+    let rust = emit(&api, Language::Rust, Options::default());
+    let wrappers = "\
+pub unsafe fn fetch(c: *mut ctx) -> (u64, batch) { \
+let mut b: batch = core::mem::zeroed(); (fetch_v1(c, &mut b), b) }
+pub unsafe fn two() -> (u32, batch) { \
+let mut a: u32 = core::mem::zeroed(); let mut b: batch = core::mem::zeroed(); two_v1(&mut a, &mut b); (a, b) }
+/// # Arguments
+///
+/// * `b` - The batch to update
+pub unsafe fn upd(b: &mut batch, n: Option<&mut u32>) { upd_v1(b, n.map_or(core::ptr::null_mut(), |p| p)) }
+";
+    assert!(rust.ends_with(&format!("{}{}", wrappers, rust::POSTAMBLE)));
+}
+
+#[test]
+fn emit_errors() {
+    let src = "\
+errors ex_err: u32 {
+  OK = 0 \"ok\",
+  AGAIN = 1 \"try again\",
+}
+#[returns_status(ex_err)]
+fn(v1) poll() -> ex_err;
+#[returns_status(ex_err)]
+fn(v1) read(#[out] n: *u32) -> u32;";
+    let api = parse(src, None).unwrap();
+
+    let c = emit(&api, Language::C, Options::default());
+    let errors = "\
+typedef uint32_t ex_err_t;
+#define EX_ERR_OK ((ex_err_t)(0))
+#define EX_ERR_AGAIN ((ex_err_t)(1))
+static inline const char* ex_strerror(ex_err_t err) {
+  switch (err) {
+    case EX_ERR_OK: return \"ok\";
+    case EX_ERR_AGAIN: return \"try again\";
+    default: return \"unknown error\";
+  }
+}";
+    assert!(c.contains(errors));
+
+    let rust = emit(&api, Language::Rust, Options::default());
+    let errors = "\
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ex_err(pub u32);
+impl ex_err {
+  pub const OK: ex_err = ex_err(0);
+  pub const AGAIN: ex_err = ex_err(1);
+  pub fn message(&self) -> &'static str {
+    match *self {
+      ex_err::OK => \"ok\",
+      ex_err::AGAIN => \"try again\",
+      _ => \"unknown error\",
+    }
+  }
+}
+impl core::fmt::Display for ex_err {
+  fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result { f.write_str(self.message()) }
+}
+impl std::error::Error for ex_err {}";
+    assert!(rust.contains(errors));
+
+    // the wrappers check the status, and return the out args on success
+    let wrappers = "\
+pub unsafe fn poll() -> Result<(), ex_err> { match poll_v1() { ex_err(0) => Ok(()), err => Err(err) } }
+pub unsafe fn read() -> Result<u32, ex_err> { \
+let mut n: u32 = core::mem::zeroed(); match ex_err(read_v1(&mut n)) { ex_err(0) => Ok(n), err => Err(err) } }
+";
+    assert!(rust.ends_with(&format!("{}{}", wrappers, rust::POSTAMBLE)));
+}
+
+#[test]
+fn emit_ownership() {
+    let src = "\
+opaque ctx;
+#[constructor]
+fn(v1) new(n: u32) -> *ctx;
+#[destructor(ctx)]
+fn(v1) delete(c: *ctx);
+#[destructor(ctx)]
+fn(v2) delete(c: *ctx!);";
+    let api = parse(src, None).unwrap();
+
+    // the Rust handle releases with the latest destructor
+    let rust = emit(&api, Language::Rust, Options::default());
+    let handles = "\
+/// An owned `ctx`: released with `delete_v2` when dropped
+pub struct ctx_handle(core::ptr::NonNull<ctx>);
+impl ctx_handle {
+  /// # Safety
+  /// `p` must be owned by the caller, and not released elsewhere
+  pub unsafe fn from_raw(p: *mut ctx) -> Option<Self> { core::ptr::NonNull::new(p).map(Self) }
+  pub fn as_ptr(&self) -> *mut ctx { self.0.as_ptr() }
+  pub fn into_raw(self) -> *mut ctx { let p = self.0.as_ptr(); core::mem::forget(self); p }
+}
+impl Drop for ctx_handle {
+  fn drop(&mut self) { unsafe { delete_v2(self.0.as_ptr()) } }
+}
+";
+    assert!(rust.contains(&format!("{}{}", rust::HANDLES_HEADER, handles)));
+
+    // constructors return handles
+    let new = "pub unsafe fn new(n: u32) -> Option<ctx_handle> { ctx_handle::from_raw(new_v1(n)) }";
+    assert!(rust.contains(new));
+
+    // the C header is unchanged
+    let c = emit(&api, Language::C, Options::default());
+    assert!(c.contains("DANDIYA_API_EXPORT ctx_t* DANDIYA_NULL_UNSPECIFIED new_v1(uint32_t n);"));
+}
+
+#[test]
+fn emit_refcounted() {
+    let src = "\
+#[refcounted(retain = buf_ref, release = buf_unref)]
+opaque buf;
+fn(v1) buf_ref(b: *buf) -> *buf;
+fn(v2) buf_ref(b: *buf);
+fn(v1) buf_unref(b: *buf);";
+    let api = parse(src, None).unwrap();
+
+    // the Rust handle retains when cloned, and releases when dropped
+    let rust = emit(&api, Language::Rust, Options::default());
+    let handles = "\
+/// A counted reference to a `buf`: retained with `buf_ref_v2` when cloned, and released with `buf_unref_v1` when dropped
+pub struct buf_handle(core::ptr::NonNull<buf>);
+impl buf_handle {
+  /// # Safety
+  /// `p` must be owned by the caller, and not released elsewhere
+  pub unsafe fn from_raw(p: *mut buf) -> Option<Self> { core::ptr::NonNull::new(p).map(Self) }
+  pub fn as_ptr(&self) -> *mut buf { self.0.as_ptr() }
+  pub fn into_raw(self) -> *mut buf { let p = self.0.as_ptr(); core::mem::forget(self); p }
+}
+impl Clone for buf_handle {
+  fn clone(&self) -> Self { unsafe { buf_ref_v2(self.0.as_ptr()); } Self(self.0) }
+}
+impl Drop for buf_handle {
+  fn drop(&mut self) { unsafe { buf_unref_v1(self.0.as_ptr()) } }
+}
+";
+    assert!(rust.contains(&format!("{}{}", rust::HANDLES_HEADER, handles)));
+
+    // the C header is unchanged
+    let c = emit(&api, Language::C, Options::default());
+    assert!(c.contains("typedef struct buf buf_t;\n"));
+}
+
+#[test]
+fn emit_field_defaults() {
+    let src = "\
+const MAX: u16 = 1500;
+struct batch {
+  num: u8 = 0,
+  max: u64 = MAX,
+  dat: [u8; 1500],
+}
+struct queue {
+  head: batch,
+}";
+
+    let c = "\
+#define MAX ((uint16_t)(1500))
+typedef struct batch batch_t;
+struct batch {
+  uint8_t num;
+  uint64_t max;
+  uint8_t dat[1500];
+};
+#ifndef __cplusplus
+#define BATCH_INIT { .num = 0, .max = MAX }
+#endif
+static inline batch_t batch_init(void) {
+  batch_t s;
+  __builtin_memset(&s, 0, sizeof s);
+  s.num = 0;
+  s.max = MAX;
+  return s;
+}
+typedef struct queue queue_t;
+struct queue {
+  batch_t head;
+};
+#ifndef __cplusplus
+#define QUEUE_INIT { .head = BATCH_INIT }
+#endif
+static inline queue_t queue_init(void) {
+  queue_t s;
+  __builtin_memset(&s, 0, sizeof s);
+  s.head = batch_init();
+  return s;
+}";
+
+    let rust = "\
+pub const MAX: u16 = 1500;
+#[repr(C)]
+pub struct batch {
+  pub num: u8,
+  pub max: u64,
+  pub dat: [u8; 1500],
+}
+impl Default for batch {
+  fn default() -> Self {
+    let mut s: Self = unsafe { core::mem::zeroed() };
+    s.num = 0;
+    s.max = MAX as u64;
+    s
+  }
+}
+#[repr(C)]
+pub struct queue {
+  pub head: batch,
+}
+impl Default for queue {
+  fn default() -> Self {
+    let mut s: Self = unsafe { core::mem::zeroed() };
+    s.head = Default::default();
+    s
+  }
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_sized_opaque() {
+    let src = "\
+const LOCK_SIZE: u64 = 64;
+opaque lock(size = LOCK_SIZE, align = 8);
+opaque small(size = 3);
+opaque big(size = LOCK_SIZE * 4, align = 16);";
+
+    let c = "\
+#define LOCK_SIZE ((uint64_t)(64))
+typedef struct lock lock_t;
+struct lock {
+  unsigned char _data[LOCK_SIZE];
+} __attribute__((aligned(8)));
+typedef struct small small_t;
+struct small {
+  unsigned char _data[3];
+};
+typedef struct big big_t;
+struct big {
+  unsigned char _data[LOCK_SIZE * 4ULL];
+} __attribute__((aligned(16)));";
+
+    let rust = "\
+pub const LOCK_SIZE: u64 = 64;
+#[repr(C, align(8))]
+pub struct lock {_data: [u8; LOCK_SIZE as usize]}
+#[repr(C)]
+pub struct small {_data: [u8; 3]}
+#[repr(C, align(16))]
+pub struct big {_data: [u8; (LOCK_SIZE as usize) * 4]}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_bits() {
+    let src = "\
+struct desc {
+  flags: u32 { valid: 1, kind: 3 @ 4 },
+}";
+
+    let c = "\
+typedef struct desc desc_t;
+struct desc {
+  uint32_t flags;
+};
+static inline uint32_t desc_flags_valid(const desc_t* s) { return (uint32_t)((s->flags >> 0) & 0x1U); }
+static inline void desc_set_flags_valid(desc_t* s, uint32_t v) { s->flags = (uint32_t)((s->flags & ~(0x1U << 0)) | ((v & 0x1U) << 0)); }
+static inline uint32_t desc_flags_kind(const desc_t* s) { return (uint32_t)((s->flags >> 4) & 0x7U); }
+static inline void desc_set_flags_kind(desc_t* s, uint32_t v) { s->flags = (uint32_t)((s->flags & ~(0x7U << 4)) | ((v & 0x7U) << 4)); }";
+
+    let rust = "\
+#[repr(C)]
+pub struct desc {
+  pub flags: u32,
+}
+impl desc {
+  pub fn flags_valid(&self) -> u32 { (self.flags >> 0) & 0x1 }
+  pub fn set_flags_valid(&mut self, v: u32) { self.flags = (self.flags & !(0x1 << 0)) | ((v & 0x1) << 0); }
+  pub fn flags_kind(&self) -> u32 { (self.flags >> 4) & 0x7 }
+  pub fn set_flags_kind(&mut self, v: u32) { self.flags = (self.flags & !(0x7 << 4)) | ((v & 0x7) << 4); }
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_platform_types() {
+    let src = "\
+struct s {
+  f: f32,
+  d: f64,
+  name: *c_char,
+  i: c_int,
+  l: c_ulonglong,
+}";
+
+    let c = "\
+typedef struct s s_t;
+struct s {
+  float f;
+  double d;
+  char* name;
+  int i;
+  unsigned long long l;
+};";
+
+    let rust = "\
+#[repr(C)]
+pub struct s {
+  pub f: f32,
+  pub d: f64,
+  pub name: *mut core::ffi::c_char,
+  pub i: core::ffi::c_int,
+  pub l: core::ffi::c_ulonglong,
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_c_includes() {
+    let options = Options {
+        api_forward_to_latest: false,
+        ..Default::default()
+    };
+    let includes = |src: &str| {
+        let c = emit(&parse(src, None).unwrap(), Language::C, options.clone());
+        c.lines()
+            .filter(|l| l.starts_with("#include"))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(includes("fn(v1) f(a: u8);"), ["#include <stdint.h>"]);
+    assert_eq!(
+        includes("fn(v1) f(a: u8) -> bool;"),
+        ["#include <stdint.h>", "#include <stdbool.h>"]
+    );
+    assert_eq!(
+        includes("struct s { f: [fn(n: isize); 2] }"),
+        ["#include <stdint.h>", "#include <stddef.h>"]
+    );
+    assert_eq!(
+        includes("fn(v1) f(a: bool, n: usize);"),
+        [
+            "#include <stdint.h>",
+            "#include <stdbool.h>",
+            "#include <stddef.h>"
+        ]
+    );
 }
 
 #[test]
@@ -103,7 +868,7 @@ fn emit_no_args() {
     let c = "DANDIYA_API_EXPORT uint32_t func_v1(void);";
     let rust = "extern \"C\" { pub fn func_v1() -> u32; }";
 
-    check(src, &c, rust);
+    check(src, c, rust);
 }
 
 #[test]
@@ -112,7 +877,549 @@ fn emit_const() {
     let c = "#define MYCONST ((uint64_t)(4235))";
     let rust = "pub const MYCONST: u64 = 4235;";
 
-    check(src, &c, rust);
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_typed_const() {
+    let src = "\
+const MAX_PKT: u16 = 0x5DC;
+const MIN: i32 = -5;
+const NAME = \"example\\n\";";
+
+    let c = "\
+#define MAX_PKT ((uint16_t)(0x5DC))
+#define MIN ((int32_t)(-5))
+#define NAME \"example\\n\"";
+
+    let rust = "\
+pub const MAX_PKT: u16 = 0x5DC;
+pub const MIN: i32 = -5;
+pub const NAME: &str = \"example\\n\";";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_i64_min() {
+    // C has no literal for the minimum i64
+    let src = "\
+const MIN: i64 = -9223372036854775808;
+const NEXT: i64 = -9223372036854775808 + 1;
+enum lo: i64 { MIN = -9223372036854775808 }";
+
+    let c = "\
+#define MIN ((int64_t)((-9223372036854775807LL - 1)))
+#define NEXT ((int64_t)((-9223372036854775807LL - 1) + 1LL))
+typedef int64_t lo_t;
+#define LO_MIN ((lo_t)((-9223372036854775807LL - 1)))";
+
+    let rust = "\
+pub const MIN: i64 = -9223372036854775808;
+pub const NEXT: i64 = -9223372036854775808 + 1;
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct lo(pub i64);
+impl lo {
+  pub const MIN: lo = lo(-9223372036854775808);
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_const_expr() {
+    let src = "\
+const MAX_PKT: u16 = 0x5DC;
+const TOTAL: u32 = MAX_PKT + 14 * 2;
+const MASK: u64 = (1 << 40) - 1 | 0xf & 3;
+const NEG: i32 = - -1;
+struct packet {
+  dat: [u8; MAX_PKT],
+  hdr: [u8; (MAX_PKT + 2) * 4],
+}";
+
+    let c = "\
+#define MAX_PKT ((uint16_t)(0x5DC))
+#define TOTAL ((uint32_t)(MAX_PKT + 14U * 2U))
+#define MASK ((uint64_t)(((1ULL << 40) - 1ULL) | (0xfULL & 3ULL)))
+#define NEG ((int32_t)(-(-1)))
+typedef struct packet packet_t;
+struct packet {
+  uint8_t dat[MAX_PKT];
+  uint8_t hdr[((uint64_t)MAX_PKT + 2ULL) * 4ULL];
+};";
+
+    let rust = "\
+pub const MAX_PKT: u16 = 0x5DC;
+pub const TOTAL: u32 = (MAX_PKT as u32) + 14 * 2;
+pub const MASK: u64 = ((1 << 40) - 1) | (0xf & 3);
+pub const NEG: i32 = -(-1);
+#[repr(C)]
+pub struct packet {
+  pub dat: [u8; MAX_PKT as usize],
+  pub hdr: [u8; ((MAX_PKT as usize) + 2) * 4],
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_wide_array_len() {
+    let src = "\
+const MAX: u32 = 1 << 20;
+struct big {
+  a: [u8; 1 << 40],
+  b: [u8; MAX * MAX],
+}";
+
+    let c = "\
+#define MAX ((uint32_t)(1U << 20))
+typedef struct big big_t;
+struct big {
+  uint8_t a[1ULL << 40];
+  uint8_t b[(uint64_t)MAX * (uint64_t)MAX];
+};";
+
+    let rust = "\
+pub const MAX: u32 = 1 << 20;
+#[repr(C)]
+pub struct big {
+  pub a: [u8; 1 << 40],
+  pub b: [u8; (MAX as usize) * (MAX as usize)],
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_enum() {
+    let src = "\
+enum status: u32 {
+  SUCCESS = 0,
+  ERR_AGAIN = 1,
+}
+fn(v1) func() -> status;";
+
+    let c = "\
+typedef uint32_t status_t;
+#define STATUS_SUCCESS ((status_t)(0))
+#define STATUS_ERR_AGAIN ((status_t)(1))
+DANDIYA_API_EXPORT status_t func_v1(void);";
+
+    let rust = "\
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct status(pub u32);
+impl status {
+  pub const SUCCESS: status = status(0);
+  pub const ERR_AGAIN: status = status(1);
+}
+extern \"C\" { pub fn func_v1() -> status; }";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_flags() {
+    let src = "flags opts: u16 { NONBLOCK = 0x1, ZEROCOPY = 0x10 }";
+
+    let c = "\
+typedef uint16_t opts_t;
+#define OPTS_NONBLOCK ((opts_t)(0x1))
+#define OPTS_ZEROCOPY ((opts_t)(0x10))";
+
+    let rust = "\
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct opts(pub u16);
+impl opts {
+  pub const NONBLOCK: opts = opts(0x1);
+  pub const ZEROCOPY: opts = opts(0x10);
+  pub const fn empty() -> opts { opts(0) }
+  pub const fn bits(self) -> u16 { self.0 }
+  pub const fn contains(self, other: opts) -> bool { self.0 & other.0 == other.0 }
+}
+impl core::ops::BitOr for opts {
+  type Output = opts;
+  fn bitor(self, rhs: opts) -> opts { opts(self.0 | rhs.0) }
+}
+impl core::ops::BitAnd for opts {
+  type Output = opts;
+  fn bitand(self, rhs: opts) -> opts { opts(self.0 & rhs.0) }
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_attributes() {
+    // Namespaced attributes are for other tools and don't change the output
+    let src = "
+// Some comment here
+#[x::foo(a = 1)]
+// and after the attributes
+/// Doc for Foo
+struct Foo {
+  #[x::bar] a: u8,
+}";
+
+    let c = "
+// Some comment here
+// and after the attributes
+/** Doc for Foo */
+typedef struct Foo Foo_t;
+struct Foo {
+  uint8_t a;
+};";
+
+    let rust = "
+// Some comment here
+// and after the attributes
+/// Doc for Foo
+#[repr(C)]
+pub struct Foo {
+  pub a: u8,
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_layout_attributes() {
+    let src = "\
+#[packed]
+struct hdr {
+  kind: u8,
+  #[pad] reserved: [u8; 1],
+  len: u32,
+  #[pad] reserved: [u8; 2],
+}
+#[align(64)]
+struct dma {
+  buf: [u8; 100],
+}";
+
+    let c = "\
+typedef struct hdr hdr_t;
+struct hdr {
+  uint8_t kind;
+  uint8_t _pad0[1];
+  uint32_t len;
+  uint8_t _pad1[2];
+} __attribute__((packed));
+typedef struct dma dma_t;
+struct dma {
+  uint8_t buf[100];
+} __attribute__((aligned(64)));";
+
+    let rust = "\
+#[repr(C, packed)]
+pub struct hdr {
+  pub kind: u8,
+  pub _pad0: [u8; 1],
+  pub len: u32,
+  pub _pad1: [u8; 2],
+}
+#[repr(C, align(64))]
+pub struct dma {
+  pub buf: [u8; 100],
+}";
+
+    check(src, c, rust);
+
+    // without the attribute, a field named "pad" is an ordinary field
+    let src = "struct s { pad: u32 }";
+    let c = "\
+typedef struct s s_t;
+struct s {
+  uint32_t pad;
+};";
+    let rust = "\
+#[repr(C)]
+pub struct s {
+  pub pad: u32,
+}";
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_doc_comments() {
+    let src = "
+/// A packet
+struct packet {
+  /// Length
+  /// in bytes
+  len: u16,
+}
+
+/// Fetch a packet
+fn(v1) fetch(
+  /// Where to write */ it
+  p: *packet,
+  n: u32,
+) /// Bytes fetched
+  -> u64;
+/// Dangling";
+
+    let c = "
+/** A packet */
+typedef struct packet packet_t;
+struct packet {
+  /**
+   * Length
+   * in bytes
+   */
+  uint16_t len;
+};
+
+/**
+ * Fetch a packet
+ *
+ * @param p Where to write * / it
+ * @return Bytes fetched
+ */
+DANDIYA_API_EXPORT uint64_t fetch_v1(packet_t* p, uint32_t n);
+// Dangling";
+
+    let rust = "
+/// A packet
+#[repr(C)]
+pub struct packet {
+  /// Length
+  /// in bytes
+  pub len: u16,
+}
+
+extern \"C\" {
+  /// Fetch a packet
+  ///
+  /// # Arguments
+  ///
+  /// * `p` - Where to write */ it
+  ///
+  /// # Returns
+  ///
+  /// Bytes fetched
+  pub fn fetch_v1(p: *mut packet, n: u32) -> u64;
+}
+// Dangling";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_import() {
+    // imported decls are left to the imported file's bindings
+    let src = "\
+// Shared types
+import \"tests/imports/common/types.dy\";
+struct batch {
+  bufs: [buffer; MAX_PKT / 100],
+}";
+
+    let c = "\
+// Shared types
+#include \"types.h\"
+typedef struct batch batch_t;
+struct batch {
+  buffer_t bufs[(uint64_t)MAX_PKT / 100ULL];
+};";
+
+    let rust = "\
+// Shared types
+use super::types::*;
+#[repr(C)]
+pub struct batch {
+  pub bufs: [buffer; (MAX_PKT as usize) / 100],
+}";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_library() {
+    let src = "\
+// header
+library example { version = \"1.4.0\", soname = \"libexample.so.1\", link = \"example\" }
+
+fn(v1) f();";
+    let options = Options {
+        api_forward_to_latest: false,
+        ..Default::default()
+    };
+    let api = parse(src, None).unwrap();
+
+    let c = emit(&api, Language::C, options.clone());
+    let expected_c = "\
+/*******************************************************************************
+ * Autogenerated by Dandiya API Generator
+ * Library: example 1.4.0
+ ******************************************************************************/
+#ifndef EXAMPLE_H
+#define EXAMPLE_H
+#include <stdint.h>
+
+#ifndef EXAMPLE_API_EXPORT
+#  if defined(__GNUC__) || defined(__clang__)
+#    define EXAMPLE_API_EXPORT __attribute__((visibility(\"default\")))
+#  else
+#    error Unsupported compiler
+#  endif
+#endif
+
+#define EXAMPLE_VERSION \"1.4.0\"
+#define EXAMPLE_SONAME \"libexample.so.1\"
+
+#ifdef __cplusplus
+extern \"C\" {
+#endif
+
+// header
+EXAMPLE_API_EXPORT void f_v1(void);
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* EXAMPLE_H */
+";
+    assert_eq!(c, expected_c);
+
+    let rust = emit(&api, Language::Rust, options.clone());
+    let expected_rust = "\
+/*******************************************************************************
+ * Autogenerated by Dandiya API Generator
+ * Library: example 1.4.0
+ ******************************************************************************/
+#![allow(dead_code)]
+
+#[link(name = \"example\")]
+extern \"C\" {}
+pub const EXAMPLE_VERSION: &str = \"1.4.0\";
+pub const EXAMPLE_SONAME: &str = \"libexample.so.1\";
+
+// header
+extern \"C\" { pub fn f_v1(); }";
+    assert_eq!(rust, expected_rust);
+
+    // without a link name, nothing is linked
+    let api = parse("library example {}", None).unwrap();
+    let rust = emit(&api, Language::Rust, options.clone());
+    assert!(!rust.contains("#[link"));
+}
+
+#[test]
+fn emit_alias() {
+    let src = "\
+opaque ctx;
+/// A handle
+type handle = u64;
+type ctxp = *const ctx;
+type cb = fn(c: ctxp) -> i32;
+const MAX: handle = 1 << 40;
+fn(v1) get(c: ctxp, f: cb) -> handle;";
+
+    let c = "\
+typedef struct ctx ctx_t;
+/** A handle */
+typedef uint64_t handle_t;
+typedef const ctx_t* ctxp_t;
+typedef int32_t (*cb_t)(ctxp_t c);
+#define MAX ((uint64_t)(1ULL << 40))
+DANDIYA_API_EXPORT handle_t get_v1(ctxp_t c, cb_t f);";
+
+    let rust = "\
+#[repr(C)]
+pub struct ctx {_opaque_data: [u8; 0]}
+/// A handle
+pub type handle = u64;
+pub type ctxp = *const ctx;
+pub type cb = Option<unsafe extern \"C\" fn(c: ctxp) -> i32>;
+pub const MAX: u64 = 1 << 40;
+extern \"C\" { pub fn get_v1(c: ctxp, f: cb) -> handle; }";
+
+    check(src, c, rust);
+}
+
+#[test]
+fn emit_symbol_prefix() {
+    let src = "\
+prefix ex_;
+const MAX: u16 = 4;
+opaque ctx;
+struct s {
+  c: *ctx,
+  d: [u8; MAX * 2],
+}
+fn(v1) new() -> *ctx;";
+
+    let c = "\
+#define EX_MAX ((uint16_t)(4))
+typedef struct ex_ctx ex_ctx_t;
+typedef struct ex_s ex_s_t;
+struct ex_s {
+  ex_ctx_t* c;
+  uint8_t d[(uint64_t)EX_MAX * 2ULL];
+};
+DANDIYA_API_EXPORT ex_ctx_t* ex_new_v1(void);";
+
+    let rust = "\
+pub const EX_MAX: u16 = 4;
+#[repr(C)]
+pub struct ex_ctx {_opaque_data: [u8; 0]}
+#[repr(C)]
+pub struct ex_s {
+  pub c: *mut ex_ctx,
+  pub d: [u8; (EX_MAX as usize) * 2],
+}
+extern \"C\" { pub fn ex_new_v1() -> *mut ex_ctx; }";
+
+    check(src, c, rust);
+
+    // optionally, a module re-exports the unprefixed names
+    let options = Options {
+        rust_unprefixed_module: true,
+        ..Default::default()
+    };
+    let rust = emit(&parse(src, None).unwrap(), Language::Rust, options);
+    let module = "
+/*******************************************************************************
+ * Unprefixed Names
+ ******************************************************************************/
+pub mod ex {
+  pub use super::EX_MAX as MAX;
+  pub use super::ex_ctx as ctx;
+  pub use super::ex_s as s;
+  pub use super::ex_new_v1 as new_v1;
+  pub use super::ex_new as new;
+}
+";
+    assert!(rust.ends_with(&format!("{}{}", module, rust::POSTAMBLE)));
+}
+
+#[test]
+fn emit_unprefixed_keywords() {
+    let src = "\
+prefix ex_;
+opaque type;
+struct self { a: u8 }
+fn(v1) match(t: *type) -> u8;";
+    let options = Options {
+        rust_unprefixed_module: true,
+        ..Default::default()
+    };
+    let rust = emit(&parse(src, None).unwrap(), Language::Rust, options);
+
+    // keywords become raw identifiers, or get a trailing '_' if they can't be
+    let module = "\
+pub mod ex {
+  pub use super::ex_type as r#type;
+  pub use super::ex_self as self_;
+  pub use super::ex_match_v1 as match_v1;
+  pub use super::ex_match as r#match;
+}
+";
+    assert!(rust.ends_with(&format!("{}{}", module, rust::POSTAMBLE)));
 }
 
 #[test]
@@ -159,5 +1466,5 @@ extern \"C\" { pub fn foo_v1(); }
 
   //Trailingcomment";
 
-    check(src, &c, rust);
+    check(src, c, rust);
 }
@@ -1,3 +1,4 @@
+use dandiya::ast::*;
 use dandiya::parse::*;
 
 #[test]
@@ -70,8 +71,76 @@ fn tok_num() {
     let mut tok = Tokenizer::new("18446744073709551616", None);
     tok.next_tok().err().unwrap();
 
-    // negative numbers not allowed currently
-    let mut tok = Tokenizer::new("-1", None);
+    let mut tok = Tokenizer::new("0x0 0x1f 0xFFFFFFFFFFFFFFFF", None);
+    assert_eq!(tok.next_tok().unwrap().1, Token::U64(0));
+    assert_eq!(tok.next_tok().unwrap().1, Token::U64(0x1f));
+    assert_eq!(tok.next_tok().unwrap().1, Token::U64(u64::MAX));
+    assert_eq!(tok.next_tok().unwrap().1, Token::EndOfFile);
+
+    // overflow u64
+    let mut tok = Tokenizer::new("0x10000000000000000", None);
+    tok.next_tok().err().unwrap();
+
+    // hex prefix without digits
+    let mut tok = Tokenizer::new("0x", None);
+    tok.next_tok().err().unwrap();
+
+    // negative numbers are a '-' token followed by a number
+    let mut tok = Tokenizer::new("-1 ->", None);
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc('-'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::U64(1));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Arrow);
+    assert_eq!(tok.next_tok().unwrap().1, Token::EndOfFile);
+}
+
+#[test]
+fn tok_operators() {
+    let mut tok = Tokenizer::new("+-*/<<&|", None);
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc('+'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc('-'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc('*'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc('/'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Shl);
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc('&'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc('|'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::EndOfFile);
+
+    let mut tok = Tokenizer::new("<", None);
+    tok.next_tok().err().unwrap();
+}
+
+#[test]
+fn tok_attr() {
+    let mut tok = Tokenizer::new("#[x::y]", None);
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc('#'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc('['));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Ident("x".to_string()));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc(':'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc(':'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Ident("y".to_string()));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Punc(']'));
+    assert_eq!(tok.next_tok().unwrap().1, Token::EndOfFile);
+}
+
+#[test]
+fn tok_str() {
+    let mut tok = Tokenizer::new("\"\" \"foo bar\" \"a\\\"b\\n\"", None);
+    assert_eq!(tok.next_tok().unwrap().1, Token::Str("".to_string()));
+    assert_eq!(tok.next_tok().unwrap().1, Token::Str("foo bar".to_string()));
+    assert_eq!(
+        tok.next_tok().unwrap().1,
+        Token::Str("a\\\"b\\n".to_string())
+    );
+    assert_eq!(tok.next_tok().unwrap().1, Token::EndOfFile);
+
+    // unterminated
+    let mut tok = Tokenizer::new("\"foo", None);
+    tok.next_tok().err().unwrap();
+    let mut tok = Tokenizer::new("\"foo\nbar\"", None);
+    tok.next_tok().err().unwrap();
+
+    // escapes that differ between C and Rust
+    let mut tok = Tokenizer::new("\"\\x41\"", None);
     tok.next_tok().err().unwrap();
 }
 
@@ -97,11 +166,13 @@ fn tok_ident_and_kw() {
 
 #[test]
 fn tok_kws() {
-    let mut tok = Tokenizer::new("fn struct opaque const", None);
+    let mut tok = Tokenizer::new("fn struct opaque const enum union", None);
     assert_eq!(tok.next_tok().unwrap().1, Token::Fn);
     assert_eq!(tok.next_tok().unwrap().1, Token::Struct);
     assert_eq!(tok.next_tok().unwrap().1, Token::Opaque);
     assert_eq!(tok.next_tok().unwrap().1, Token::Const);
+    assert_eq!(tok.next_tok().unwrap().1, Token::Enum);
+    assert_eq!(tok.next_tok().unwrap().1, Token::Union);
     assert_eq!(tok.next_tok().unwrap().1, Token::EndOfFile);
 }
 
@@ -165,6 +236,40 @@ fn parse_struct() {
     parse(s, None).unwrap();
 }
 
+#[test]
+fn parse_struct_with_platform_types() {
+    let s = "\
+      struct Foobar {
+        ok: bool,
+        f: f32,
+        d: f64,
+        n: usize,
+        i: isize,
+        name: *c_char,
+        a: c_schar,
+        b: c_uchar,
+        c: c_short,
+        e: c_ushort,
+        g: c_int,
+        h: c_uint,
+        j: c_long,
+        k: c_ulong,
+        l: c_longlong,
+        m: c_ulonglong,
+      }
+     ";
+    parse(s, None).unwrap();
+
+    // platform-dependent or non-integer types can't be used where a
+    // fixed-width integer is required
+    let s = "const A: usize = 1;";
+    parse(s, None).err().unwrap();
+    let s = "enum e: c_int { A = 0 }";
+    parse(s, None).err().unwrap();
+    let s = "flags e: bool { A = 1 }";
+    parse(s, None).err().unwrap();
+}
+
 #[test]
 fn parse_struct_with_ptr() {
     let s = "\
@@ -208,6 +313,945 @@ fn parse_struct_with_complex_types() {
     parse(s, None).err().unwrap();
 }
 
+#[test]
+fn parse_union() {
+    let s = "\
+      struct Baz {
+        ptr: *u8,
+      }
+      union Foobar {
+        foo: u8,
+        bar: *i32,
+        baz: Baz,
+        arr: [u64; 42],
+      }
+      struct Qux {
+        u: Foobar,
+      }
+     ";
+    parse(s, None).unwrap();
+
+    // C and Rust don't allow empty unions
+    let s = "union A {}";
+    parse(s, None).err().unwrap();
+
+    // Same restrictions on member types as structs
+    let s = "union A { arr: [[u8; 2]; 2] }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_variant() {
+    let s = "\
+      struct packet {
+        len: u16,
+      }
+      variant event {
+        Packet(packet),
+        Error(u32),
+        Ptr(*u8),
+        Idle,
+      }
+      variant units: u8 { A, B, }
+      fn(v1) poll(ev: *event);
+     ";
+    parse(s, None).unwrap();
+
+    let s = "variant event {}";
+    parse(s, None).err().unwrap();
+
+    // Tag constants are upper-cased so must be unique ignoring case
+    let s = "variant event { Idle, IDLE }";
+    parse(s, None).err().unwrap();
+
+    // Tag type must be an integer that can hold every case
+    let s = "variant event: packet { Idle }";
+    parse(s, None).err().unwrap();
+    let s = "variant event: u8 { A, B }";
+    parse(s, None).unwrap();
+
+    // Same restrictions on payload types as struct fields
+    let s = "variant event { Arr([[u8; 2]; 2]) }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_fn_ptrs() {
+    let s = "\
+      opaque ctx;
+      callback cb(c: *ctx, n: u32) -> i32;
+      callback void_cb();
+      struct Foobar {
+        f: fn(c: *ctx, n: u32) -> i32,
+        arr: [fn(); 4],
+        ptr: *fn() -> *u8,
+        named: cb,
+        ret: fn() -> fn() -> u8,
+      }
+      fn(v1) reg(c: *ctx, f: fn(c: *ctx) -> u8, g: cb) -> fn();
+     ";
+    parse(s, None).unwrap();
+
+    // fn pointers can't return arrays
+    let s = "struct A { f: fn() -> [u8; 4] }";
+    parse(s, None).err().unwrap();
+    let s = "callback cb() -> [u8; 4];";
+    parse(s, None).err().unwrap();
+
+    // args are named
+    let s = "struct A { f: fn(u8) }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_const_pointers() {
+    let s = "\
+      struct Foobar {
+        a: *const u8,
+        b: *mut u8,
+        c: *const *mut Foobar,
+        d: *const fn(p: *const u8),
+      }
+      fn(v1) copy(dst: *mut u8, src: *const u8, n: u64) -> *const u8;
+     ";
+    parse(s, None).unwrap();
+
+    let s = "struct A { a: *const }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { a: *const [u8; 4] }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_nullability() {
+    let s = "\
+      opaque ctx;
+      struct Foobar {
+        a: *u8!,
+        b: *?const u8,
+        c: **?u8!,
+        d: *?fn(),
+      }
+      fn(v1) find(c: *const ctx!, hint: *?u8) -> *?ctx;
+     ";
+    parse(s, None).unwrap();
+
+    // "!" can't apply to a pointer that's already nullable
+    let s = "struct A { a: *?u8! }";
+    parse(s, None).err().unwrap();
+    // or to a non-pointer
+    let s = "struct A { a: u8! }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { a: ?*u8 }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_attributes() {
+    let s = "\
+      #[x_team::foo]
+      #[x_team::bar(key = 1, name = \"s\", mode = fast, -2, thing,)]
+      struct Foobar {
+        #[x_team::field] a: u8,
+        b: u8,
+      }
+      #[x_team::api(since = 3)]
+      fn(v1) foo(#[x_team::arg] a: *u8, b: u8);
+      #[x_team::cb] callback cb(#[x_team::arg] a: u8);
+     ";
+    let defn = parse(s, None).unwrap();
+
+    let attrs = defn.symbols["Foobar"].attrs();
+    assert_eq!(attrs.len(), 2);
+    assert_eq!(attrs[0].name, "x_team::foo");
+    assert!(attrs[0].args.is_empty());
+    assert_eq!(attrs[1].args.len(), 5);
+    assert_eq!(attrs[1].args[0].key.as_deref(), Some("key"));
+    assert_eq!(attrs[1].args[2].val, AttrValue::Ident("fast".to_string()));
+    assert_eq!(attrs[1].args[3].key, None);
+
+    // unknown attributes must be namespaced
+    let s = "#[foo] struct A {}";
+    parse(s, None).err().unwrap();
+    let s = "struct A { #[foo] a: u8 }";
+    parse(s, None).err().unwrap();
+
+    let s = "#[x::a] #[x::a] struct A {}";
+    parse(s, None).err().unwrap();
+    let s = "#[x::] struct A {}";
+    parse(s, None).err().unwrap();
+    let s = "#[x::a(b = )] struct A {}";
+    parse(s, None).err().unwrap();
+
+    // attributes must be followed by something to attach to
+    let s = "struct A {} #[x::a]";
+    parse(s, None).err().unwrap();
+    let s = "struct A { #[x::a] }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_layout_attributes() {
+    let s = "\
+      #[packed]
+      struct hdr { kind: u8, #[pad] r: [u8; 1], len: u32, #[pad] r: [u8; 2] }
+      #[align(0x40)]
+      struct dma { buf: [u8; 100] }
+      #[packed]
+      struct wire { h: hdr, d: *dma }
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Struct(hdr) = defn.symbols["hdr"].as_ref() else {
+        panic!("expected a struct");
+    };
+    assert!(hdr.packed());
+    assert_eq!(hdr.align(), None);
+    assert_eq!(hdr.field_names(), ["kind", "_pad0", "len", "_pad1"]);
+    let Decl::Struct(dma) = defn.symbols["dma"].as_ref() else {
+        panic!("expected a struct");
+    };
+    assert_eq!(dma.align(), Some(64));
+
+    // Rust can't express packed and aligned together, even when nested
+    let s = "#[packed] #[align(8)] struct A {}";
+    parse(s, None).err().unwrap();
+    let s = "#[align(8)] struct A {} struct B { a: [A; 2] } #[packed] struct C { b: B }";
+    parse(s, None).err().unwrap();
+
+    let s = "#[align(3)] struct A {}";
+    parse(s, None).err().unwrap();
+    let s = "#[align(0)] struct A {}";
+    parse(s, None).err().unwrap();
+    let s = "#[align] struct A {}";
+    parse(s, None).err().unwrap();
+    let s = "#[align(n = 8)] struct A {}";
+    parse(s, None).err().unwrap();
+    let s = "#[packed(2)] struct A {}";
+    parse(s, None).err().unwrap();
+
+    // only on structs
+    let s = "#[packed] union A { a: u8 }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { #[align(8)] a: u8 }";
+    parse(s, None).err().unwrap();
+
+    let s = "struct A { #[pad] r: u32 }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { #[pad(2)] r: [u8; 2] }";
+    parse(s, None).err().unwrap();
+    let s = "union A { a: u32, #[pad] r: [u8; 4] }";
+    parse(s, None).err().unwrap();
+    let s = "#[pad] struct A {}";
+    parse(s, None).err().unwrap();
+
+    // padding is emitted as "_pad0", "_pad1", etc, which other fields can't be
+    let s = "struct A { #[pad] r: [u8; 4], _pad0: u32 }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { _pad1: u32, #[pad] r: [u8; 4], #[pad] q: [u8; 4] }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { #[pad] r: [u8; 4], _pad1: u32 }";
+    parse(s, None).unwrap();
+
+    // without the attribute, a field named "pad" is an ordinary field
+    let s = "struct A { pad: u32, b: u8 }";
+    let defn = parse(s, None).unwrap();
+    let Decl::Struct(a) = defn.symbols["A"].as_ref() else {
+        panic!("expected a struct");
+    };
+    assert_eq!(a.field_names(), ["pad", "b"]);
+}
+
+#[test]
+fn parse_len_of() {
+    let s = "\
+      type len = u32;
+      struct pkt { n: u8 }
+      fn(v1) send(buf: *const u8, #[len_of(buf)] n: u64);
+      fn(v1) recv(#[len_of(pkts)] n: len, pkts: *?pkt);
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Fn(send) = defn.symbols["send_v1"].as_ref() else {
+        panic!("expected a fn");
+    };
+    assert_eq!(send.args[0].len_of(), None);
+    assert_eq!(send.args[1].len_of(), Some("buf"));
+
+    // must name a pointer arg of the same fn
+    let s = "fn(v1) f(buf: *u8, #[len_of(b)] n: u64);";
+    parse(s, None).err().unwrap();
+    let s = "fn(v1) f(buf: u8, #[len_of(buf)] n: u64);";
+    parse(s, None).err().unwrap();
+    let s = "opaque ctx; fn(v1) f(c: *ctx, #[len_of(c)] n: u64);";
+    parse(s, None).err().unwrap();
+    let s = "fn(v1) f(buf: *u8, #[len_of] n: u64);";
+    parse(s, None).err().unwrap();
+    let s = "fn(v1) f(buf: *u8, #[len_of(\"buf\")] n: u64);";
+    parse(s, None).err().unwrap();
+
+    // lengths are unsigned integers, one per pointer
+    let s = "fn(v1) f(buf: *u8, #[len_of(buf)] n: i64);";
+    parse(s, None).err().unwrap();
+    let s = "fn(v1) f(buf: *u8, #[len_of(buf)] n: *u64);";
+    parse(s, None).err().unwrap();
+    let s = "fn(v1) f(buf: *u8, #[len_of(buf)] n: u64, #[len_of(buf)] m: u64);";
+    parse(s, None).err().unwrap();
+
+    // only on the args of fns
+    let s = "struct A { buf: *u8, #[len_of(buf)] n: u64 }";
+    parse(s, None).err().unwrap();
+    let s = "callback cb(buf: *u8, #[len_of(buf)] n: u64);";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_directions() {
+    let s = "\
+      opaque ctx;
+      struct batch { n: u32 }
+      fn(v1) fetch(#[in] c: *const ctx, #[out] b: *batch, #[inout] n: *mut u32);
+      callback cb(#[out] n: *u32);
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Fn(fetch) = defn.symbols["fetch_v1"].as_ref() else {
+        panic!("expected a fn");
+    };
+    let directions: Vec<_> = fetch.args.iter().map(|a| a.direction()).collect();
+    assert_eq!(
+        directions,
+        [
+            Some(Direction::In),
+            Some(Direction::Out),
+            Some(Direction::InOut)
+        ]
+    );
+
+    // written args must be mutable pointers to something with a value
+    let s = "fn(v1) f(#[out] n: *const u32);";
+    parse(s, None).err().unwrap();
+    let s = "fn(v1) f(#[inout] n: u32);";
+    parse(s, None).err().unwrap();
+    let s = "opaque ctx; fn(v1) f(#[out] c: *ctx);";
+    parse(s, None).err().unwrap();
+    let s = "opaque ctx; fn(v1) f(#[out] c: **ctx);";
+    parse(s, None).unwrap();
+
+    let s = "fn(v1) f(#[in] #[out] n: *u32);";
+    parse(s, None).err().unwrap();
+    let s = "fn(v1) f(#[out(1)] n: *u32);";
+    parse(s, None).err().unwrap();
+    let s = "struct A { #[out] n: *u32 }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_errors() {
+    let s = "\
+      errors err: u32 { SUCCESS = 0 \"ok\", AGAIN = 1 \"try again\", }
+      type status = u32;
+      #[returns_status(err)]
+      fn(v1) poll() -> err;
+      #[returns_status(err)]
+      fn(v1) wait() -> status;
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Errors(err) = defn.symbols["err"].as_ref() else {
+        panic!("expected errors");
+    };
+    let codes: Vec<_> = err
+        .errors
+        .iter()
+        .map(|e| (e.name.as_str(), e.val.val, e.msg.as_str()))
+        .collect();
+    assert_eq!(codes, [("SUCCESS", 0, "ok"), ("AGAIN", 1, "try again")]);
+    assert_eq!(err.strerror_name(), "err_strerror");
+    let Decl::Fn(wait) = defn.symbols["wait_v1"].as_ref() else {
+        panic!("expected a fn");
+    };
+    assert_eq!(wait.returns_status(), Some("err"));
+
+    // statuses resolve through the prefix
+    let s =
+        "prefix ex_; errors err: u8 { OK = 0 \"ok\" } #[returns_status(err)] fn(v1) f() -> err;";
+    let defn = parse(s, None).unwrap();
+    let Decl::Fn(f) = defn.symbols["ex_f_v1"].as_ref() else {
+        panic!("expected a fn");
+    };
+    assert_eq!(f.returns_status(), Some("ex_err"));
+
+    // a single success code, with unique names and values
+    let s = "errors err: u8 { AGAIN = 1 \"try again\" }";
+    parse(s, None).err().unwrap();
+    let s = "errors err: u8 { OK = 0 \"ok\", AGAIN = 0 \"try again\" }";
+    parse(s, None).err().unwrap();
+    let s = "errors err: u8 { OK = 0 \"ok\", OK = 1 \"try again\" }";
+    parse(s, None).err().unwrap();
+    let s = "errors err: u8 { OK = 0 }";
+    parse(s, None).err().unwrap();
+    let s = "errors err: u8 { OK = 256 \"ok\" }";
+    parse(s, None).err().unwrap();
+
+    // the return type must carry the status
+    let s = "errors err: u8 { OK = 0 \"ok\" } #[returns_status(err)] fn(v1) f() -> u32;";
+    parse(s, None).err().unwrap();
+    let s = "errors err: u8 { OK = 0 \"ok\" } #[returns_status(err)] fn(v1) f();";
+    parse(s, None).err().unwrap();
+    let s = "struct err { n: u8 } #[returns_status(err)] fn(v1) f() -> err;";
+    parse(s, None).err().unwrap();
+    let s = "errors err: u8 { OK = 0 \"ok\" } #[returns_status] fn(v1) f() -> err;";
+    parse(s, None).err().unwrap();
+    let s = "errors err: u8 { OK = 0 \"ok\" } struct s { #[returns_status(err)] e: err }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_ownership() {
+    let s = "\
+      prefix ex_;
+      opaque ctx;
+      #[constructor]
+      fn(v1) new(n: u32) -> *ctx;
+      #[destructor(ctx)]
+      fn(v1) delete(c: *mut ctx);
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Fn(new) = defn.symbols["ex_new_v1"].as_ref() else {
+        panic!("expected a fn");
+    };
+    assert_eq!(new.constructs(), Some("ex_ctx"));
+    let Decl::Fn(delete) = defn.symbols["ex_delete_v1"].as_ref() else {
+        panic!("expected a fn");
+    };
+    assert_eq!(delete.destructs(), Some("ex_ctx"));
+
+    // constructors return opaque objects, which must have a destructor
+    let s = "opaque ctx; #[constructor] fn(v1) new() -> *ctx;";
+    parse(s, None).err().unwrap();
+    let s = "struct s { n: u8 } #[constructor] fn(v1) new() -> *s;";
+    parse(s, None).err().unwrap();
+    let s = "opaque ctx; #[constructor] fn(v1) new() -> *const ctx;";
+    parse(s, None).err().unwrap();
+    let s = "opaque ctx; #[constructor(ctx)] fn(v1) new() -> *ctx;";
+    parse(s, None).err().unwrap();
+
+    // destructors take a single object
+    let s = "opaque ctx; #[destructor(ctx)] fn(v1) delete(c: *ctx, n: u8);";
+    parse(s, None).err().unwrap();
+    let s = "opaque ctx; #[destructor(ctx)] fn(v1) delete(c: *ctx) -> u8;";
+    parse(s, None).err().unwrap();
+    let s = "opaque ctx; opaque other; #[destructor(ctx)] fn(v1) delete(c: *other);";
+    parse(s, None).err().unwrap();
+    let s = "opaque ctx; #[destructor] fn(v1) delete(c: *ctx);";
+    parse(s, None).err().unwrap();
+
+    // a single destructor, the same for all versions
+    let s = "\
+      opaque ctx;
+      #[destructor(ctx)] fn(v1) delete(c: *ctx);
+      #[destructor(ctx)] fn(v1) free(c: *ctx);
+     ";
+    parse(s, None).err().unwrap();
+    let s = "\
+      opaque ctx;
+      #[destructor(ctx)] fn(v1) delete(c: *ctx);
+      fn(v2) delete(c: *ctx);
+     ";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_refcounted() {
+    let s = "\
+      prefix ex_;
+      #[refcounted(retain = buf_ref, release = buf_unref)]
+      opaque buf;
+      #[constructor]
+      fn(v1) buf_new() -> *buf;
+      fn(v1) buf_ref(b: *buf) -> *buf;
+      fn(v2) buf_ref(b: *buf);
+      fn(v1) buf_unref(b: *buf);
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Opaque(buf) = defn.symbols["ex_buf"].as_ref() else {
+        panic!("expected an opaque");
+    };
+    assert_eq!(buf.retain(), Some("ex_buf_ref"));
+    assert_eq!(buf.release(), Some("ex_buf_unref"));
+
+    // both fns are needed, in this file
+    let s = "#[refcounted(retain = r)] opaque buf; fn(v1) r(b: *buf);";
+    parse(s, None).err().unwrap();
+    let s = "#[refcounted(retain = r, release = u)] opaque buf; fn(v1) r(b: *buf);";
+    parse(s, None).err().unwrap();
+    let s = "#[refcounted(retain = r, release = \"u\")] opaque buf; fn(v1) r(b: *buf); fn(v1) u(b: *buf);";
+    parse(s, None).err().unwrap();
+    let s = "#[refcounted(retain = r, release = u)] struct buf { n: u8 }";
+    parse(s, None).err().unwrap();
+
+    // and both take the object
+    let fns = [
+        ("fn(v1) r(b: *buf, n: u8);", "fn(v1) u(b: *buf);"),
+        ("fn(v1) r(b: *buf);", "fn(v1) u(b: *u8);"),
+        ("fn(v1) r(b: *buf);", "fn(v1) u(b: *buf) -> *buf;"),
+        ("fn(v1) r(b: *const buf);", "fn(v1) u(b: *buf);"),
+        ("fn(v1) r(b: *buf) -> u32;", "fn(v1) u(b: *buf);"),
+        ("fn(v1) r(b: *buf); fn(v2) r();", "fn(v1) u(b: *buf);"),
+    ];
+    for (retain, release) in fns {
+        let s = format!(
+            "#[refcounted(retain = r, release = u)] opaque buf; {} {}",
+            retain, release
+        );
+        parse(&s, None).err().unwrap();
+    }
+
+    // which isn't also destructed
+    let s = "\
+      #[refcounted(retain = r, release = u)] opaque buf;
+      fn(v1) r(b: *buf);
+      fn(v1) u(b: *buf);
+      #[destructor(buf)] fn(v1) delete(b: *buf);
+     ";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_field_defaults() {
+    let s = "\
+      const MAX: u16 = 1500;
+      type len = u16;
+      struct pkt { len: len = MAX - 2, dat: [u8; 4], flags: i8 = -1 }
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Struct(pkt) = defn.symbols["pkt"].as_ref() else {
+        panic!("expected a struct");
+    };
+    assert!(pkt.has_defaults());
+    let defaults: Vec<_> = pkt
+        .fields
+        .iter()
+        .map(|f| match &f.default {
+            Some(FieldDefault::Int(typ, e)) => Some((typ.clone(), e.val)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        defaults,
+        [Some((BaseType::U16, 1498)), None, Some((BaseType::I8, -1))]
+    );
+
+    // struct fields take the defaults of their type, but arrays of them don't
+    let s = "\
+      struct pos { x: u32 = 1 }
+      type where = pos;
+      struct plain { y: u32 }
+      struct obj { p: where, ps: [pos; 2], q: plain }
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Struct(obj) = defn.symbols["obj"].as_ref() else {
+        panic!("expected a struct");
+    };
+    assert!(obj.has_defaults());
+    assert_eq!(
+        obj.fields[0].default,
+        Some(FieldDefault::Struct("pos".to_string()))
+    );
+    assert_eq!(obj.fields[1].default, None);
+    assert_eq!(obj.fields[2].default, None);
+
+    // only integer struct fields, with values that fit
+    let s = "struct A { n: u8 = 256 }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { p: *u8 = 0 }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { d: [u8; 2] = 0 }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { f: f32 = 0 }";
+    parse(s, None).err().unwrap();
+    let s = "union A { n: u8 = 0 }";
+    parse(s, None).err().unwrap();
+    let s = "fn(v1) f(n: u8 = 0);";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_sized_opaque() {
+    let s = "\
+      const LOCK_SIZE: u64 = 64;
+      opaque lock(size = LOCK_SIZE, align = 8);
+      opaque small(size = 3,);
+      struct holder { l: lock, s: [small; 2] }
+      fn(v1) lock_init(#[out] l: *mut lock);
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Opaque(lock) = defn.symbols["lock"].as_ref() else {
+        panic!("expected an opaque");
+    };
+    assert_eq!(lock.size.as_ref().map(|e| e.val), Some(64));
+    assert_eq!(lock.align, Some(8));
+    let Decl::Opaque(small) = defn.symbols["small"].as_ref() else {
+        panic!("expected an opaque");
+    };
+    assert_eq!(small.size.as_ref().map(|e| e.val), Some(3));
+    assert_eq!(small.align, None);
+
+    let s = "opaque lock(size = 0);";
+    parse(s, None).err().unwrap();
+    let s = "opaque lock(align = 8);";
+    parse(s, None).err().unwrap();
+    let s = "opaque lock(size = 64, align = 3);";
+    parse(s, None).err().unwrap();
+    let s = "opaque lock(size = 12, align = 8);";
+    parse(s, None).err().unwrap();
+    let s = "opaque lock();";
+    parse(s, None).err().unwrap();
+
+    // an aligned opaque can't be packed either
+    let s = "opaque lock(size = 8, align = 8); #[packed] struct A { l: lock }";
+    parse(s, None).err().unwrap();
+    let s = "opaque lock(size = 8); #[packed] struct A { l: lock }";
+    parse(s, None).unwrap();
+}
+
+#[test]
+fn parse_bits() {
+    let s = "\
+      type word = u16;
+      struct desc {
+        flags: u32 { valid: 1, kind: 3, len: 12, top: 4 @ 28 } = 1,
+        w: word { hi: 8 @ 8, lo: 8 @ 0 },
+      }
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Struct(desc) = defn.symbols["desc"].as_ref() else {
+        panic!("expected a struct");
+    };
+    assert!(desc.has_bits());
+    let bits = desc.fields[0].bits.as_ref().unwrap();
+    assert_eq!(bits.repr, BaseType::U32);
+    let ranges: Vec<_> = bits
+        .ranges
+        .iter()
+        .map(|r| (r.name.as_str(), r.offset, r.width))
+        .collect();
+    assert_eq!(
+        ranges,
+        [
+            ("valid", 0, 1),
+            ("kind", 1, 3),
+            ("len", 4, 12),
+            ("top", 28, 4)
+        ]
+    );
+    assert_eq!(bits.ranges[2].mask(), 0xFFF);
+    assert!(desc.fields[0].default.is_some());
+    assert_eq!(desc.fields[1].bits.as_ref().unwrap().repr, BaseType::U16);
+
+    // ranges must fit, and not overlap
+    let s = "struct A { f: u8 { a: 4, b: 5 } }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { f: u8 { a: 2 @ 7 } }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { f: u8 { a: 4, b: 2 @ 3 } }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { f: u8 { a: 0 } }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { f: u8 { a: 1, a: 1 } }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { f: u8 {} }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { f: u64 { a: 64 } }";
+    parse(s, None).unwrap();
+
+    // only unsigned integer struct fields
+    let s = "struct A { f: i32 { a: 1 } }";
+    parse(s, None).err().unwrap();
+    let s = "struct A { f: *u32 { a: 1 } }";
+    parse(s, None).err().unwrap();
+    let s = "union A { f: u32 { a: 1 } }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_doc_comments() {
+    let s = "\
+      // not a doc
+      /// Doc for Foobar
+      ///
+      /// More doc
+      struct Foobar {
+        /// Doc for a
+        a: u8,
+        //// not a doc
+        b: u8,
+      }
+      /**
+       * Block doc
+       */
+      #[x::a]
+      /// After attributes
+      fn(v1) foo(
+        /** The arg */ a: u8,
+      ) /// The result
+        -> u8;
+      /***/ /**/ /*** not a doc */
+      opaque bar;
+     ";
+    let defn = parse(s, None).unwrap();
+
+    let Decl::Struct(st) = defn.symbols["Foobar"].as_ref() else {
+        panic!("expected a struct");
+    };
+    assert_eq!(st.docs, ["Doc for Foobar", "", "More doc"]);
+    assert_eq!(st.fields[0].docs, ["Doc for a"]);
+    assert!(st.fields[1].docs.is_empty());
+
+    let Decl::Fn(func) = defn.symbols["foo_v1"].as_ref() else {
+        panic!("expected a fn");
+    };
+    assert_eq!(func.docs, ["Block doc", "After attributes"]);
+    assert_eq!(func.args[0].docs, ["The arg"]);
+    assert_eq!(func.ret_docs, ["The result"]);
+
+    assert!(defn.symbols["bar"].docs().is_empty());
+
+    // a doc comment must document something
+    let s = "struct A { a: u8, /// dangling\n }";
+    parse(s, None).err().unwrap();
+    let s = "fn(v1) f(a: u8, /// dangling\n );";
+    parse(s, None).err().unwrap();
+}
+
+fn parse_import_test(name: &str) -> dandiya::Result<ApiDefn> {
+    let path = format!("{}/tests/imports/{}", env!("CARGO_MANIFEST_DIR"), name);
+    parse(&std::fs::read_to_string(&path).unwrap(), Some(&path))
+}
+
+#[test]
+fn parse_import() {
+    // imported symbols are usable in the importing file
+    let defn = parse_import_test("lib.dy").unwrap();
+    assert!(defn.symbols.contains_key("buffer"));
+    assert!(defn.symbols.contains_key("MAX_PKT"));
+    let Decl::Import(import) = defn.decls[0].as_ref() else {
+        panic!("expected an import");
+    };
+    assert_eq!(import.path, "common/types.dy");
+    assert_eq!(import.stem(), "types");
+    assert_eq!(import.defn.decls.len(), 2);
+
+    // but not the symbols of its imports
+    parse_import_test("transitive.dy").err().unwrap();
+
+    parse_import_test("cycle_a.dy").err().unwrap();
+    parse_import_test("duplicate.dy").err().unwrap();
+    parse_import_test("missing.dy").err().unwrap();
+
+    // without a source file, imports are relative to the working directory
+    let s = "import \"tests/imports/common/types.dy\"; const A = MAX_PKT;";
+    parse(s, None).unwrap();
+
+    let s = "import \"tests/imports/common/types\";";
+    parse(s, None).err().unwrap();
+    let s = "import types;";
+    parse(s, None).err().unwrap();
+
+    // the stem names the header and module, even before the file is read
+    let s = "import \"sub/my-types.dy\";";
+    parse(s, None).err().unwrap();
+    let s = "import \"sub/1types.dy\";";
+    parse(s, None).err().unwrap();
+    let s = "import \".dy\";";
+    parse(s, None).err().unwrap();
+
+    // "import" is only a keyword at the start of a decl
+    let s = "struct A { import: u8 }";
+    parse(s, None).unwrap();
+}
+
+#[test]
+fn parse_library() {
+    let s = "\
+      // header comment
+      library example {
+        version = \"1.4.0\",
+        soname = \"libexample.so.1\",
+        link = \"example\",
+      }
+      opaque ctx;
+     ";
+    let lib = parse(s, None).unwrap().library.unwrap();
+    assert_eq!(lib.name, "example");
+    assert_eq!(lib.version.as_deref(), Some("1.4.0"));
+    assert_eq!(lib.soname.as_deref(), Some("libexample.so.1"));
+    assert_eq!(lib.link.as_deref(), Some("example"));
+
+    let lib = parse("library example {}", None).unwrap().library.unwrap();
+    assert_eq!(lib.version, None);
+    assert!(parse("opaque ctx;", None).unwrap().library.is_none());
+
+    // must come first, and only once
+    let s = "opaque ctx; library example {}";
+    parse(s, None).err().unwrap();
+    let s = "library a {} library b {}";
+    parse(s, None).err().unwrap();
+
+    let s = "library example { name = \"x\" }";
+    parse(s, None).err().unwrap();
+    let s = "library example { link = \"a\", link = \"b\" }";
+    parse(s, None).err().unwrap();
+    let s = "library example { version = 1 }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_alias() {
+    let s = "\
+      opaque ctx;
+      type handle = u64;
+      type len = u32;
+      type ctxp = *const ctx;
+      type cb = fn(c: ctxp, n: len) -> bool;
+      const MAX: len = 0xFFFFFFFF;
+      enum kind: len { A = 0 }
+      struct s { h: handle, f: cb }
+      fn(v1) get(c: ctxp) -> len;
+     ";
+    let defn = parse(s, None).unwrap();
+    let Decl::Alias(alias) = defn.symbols["ctxp"].as_ref() else {
+        panic!("expected an alias");
+    };
+    assert!(matches!(alias.typ, Type::Pointer(Mutability::Const, _, _)));
+
+    // aliases of integer types are checked as the type they name
+    let Decl::Const(max) = defn.symbols["MAX"].as_ref() else {
+        panic!("expected a const");
+    };
+    assert!(matches!(max.val, ConstValue::Int(BaseType::U32, _)));
+    let s = "type len = u32; const MAX: len = 0x100000000;";
+    parse(s, None).err().unwrap();
+    let s = "type p = *u8; const MAX: p = 0;";
+    parse(s, None).err().unwrap();
+
+    // aliased types must be declared first, which also rules out cycles
+    let s = "type a = *b; struct b {}";
+    parse(s, None).err().unwrap();
+    let s = "type a = a;";
+    parse(s, None).err().unwrap();
+
+    let s = "type mac = [u8; 6];";
+    parse(s, None).err().unwrap();
+    let s = "type p = *u8!;";
+    parse(s, None).err().unwrap();
+    let s = "type len = u32; type len = u64;";
+    parse(s, None).err().unwrap();
+
+    // "type" is only a keyword at the start of a decl
+    let s = "struct A { type: u8 }";
+    parse(s, None).unwrap();
+}
+
+#[test]
+fn parse_symbol_prefix() {
+    let s = "\
+      library example {}
+      prefix example_;
+      const MAX: u16 = 4;
+      opaque ctx;
+      struct batch {
+        c: *ctx,
+        n: [u8; MAX * 2],
+        next: *later,
+        same: *example_ctx,
+      }
+      struct later {}
+      fn(v1) new() -> *ctx;
+     ";
+    let defn = parse(s, None).unwrap();
+    assert_eq!(defn.symbol_prefix.unwrap().name, "example_");
+    for name in [
+        "EXAMPLE_MAX",
+        "example_ctx",
+        "example_batch",
+        "example_new_v1",
+    ] {
+        assert!(defn.symbols.contains_key(name), "missing {}", name);
+    }
+    assert!(defn.apis.latest("example_new").is_some());
+
+    // references to declarations of this file are prefixed too
+    let Decl::Struct(batch) = defn.symbols["example_batch"].as_ref() else {
+        panic!("expected a struct");
+    };
+    let pointee = |i: usize| match &batch.fields[i].typ {
+        Type::Pointer(_, _, typ) => match typ.as_ref() {
+            Type::Base(BaseType::Struct(name)) => name.clone(),
+            _ => panic!("expected a named type"),
+        },
+        _ => panic!("expected a pointer"),
+    };
+    assert_eq!(pointee(0), "example_ctx");
+    assert_eq!(pointee(2), "example_later");
+    assert_eq!(pointee(3), "example_ctx");
+    let Type::Array(_, len) = &batch.fields[1].typ else {
+        panic!("expected an array");
+    };
+    let Expr::Binary(lhs, _, _) = &len.expr else {
+        panic!("expected a binary expression");
+    };
+    assert!(matches!(lhs.as_ref(), Expr::Const(name, _) if name == "EXAMPLE_MAX"));
+
+    // imported names are used as declared in their own file
+    let s = "\
+      prefix example_;
+      import \"tests/imports/common/net.dy\";
+      struct s { k: *net_sock, d: [u8; NET_MTU] }
+     ";
+    let defn = parse(s, None).unwrap();
+    assert!(defn.symbols.contains_key("net_sock"));
+    assert!(defn.symbols.contains_key("example_s"));
+
+    // names must still be unique after prefixing
+    let s = "prefix net_; import \"tests/imports/common/net.dy\"; opaque sock;";
+    parse(s, None).err().unwrap();
+    let s = "prefix net_; opaque sock; import \"tests/imports/common/net.dy\";";
+    parse(s, None).err().unwrap();
+    let s = "prefix ex_; opaque ctx; opaque ctx;";
+    parse(s, None).err().unwrap();
+
+    // and so must the C macros
+    let lib = "library ex { version = \"1.0\", } prefix ex_;";
+    parse(&format!("{} const VERSION = 3;", lib), None)
+        .err()
+        .unwrap();
+    parse(&format!("{} const H = 3;", lib), None).err().unwrap();
+    let s = "prefix ex_; enum mode: u32 { A = 0 } const MODE_A = 1;";
+    parse(s, None).err().unwrap();
+    let s = "prefix ex_; const MODE_A = 1; flags mode: u32 { A = 1 }";
+    parse(s, None).err().unwrap();
+    let s = "errors e: u32 { OK = 0 \"ok\" } const E_OK = 1;";
+    parse(s, None).err().unwrap();
+    let s = "variant v { a(u8) } const V_A = 1;";
+    parse(s, None).err().unwrap();
+    let s = "struct q { a: u8 = 1 } const Q_INIT = 2;";
+    parse(s, None).err().unwrap();
+    let s = "struct q { a: u8 } const Q_INIT = 2;";
+    parse(s, None).unwrap();
+
+    // must come before any declarations, and only once
+    let s = "opaque ctx; prefix example_;";
+    parse(s, None).err().unwrap();
+    let s = "prefix a_; prefix b_;";
+    parse(s, None).err().unwrap();
+    let s = "prefix __;";
+    parse(s, None).err().unwrap();
+    let s = "prefix example_";
+    parse(s, None).err().unwrap();
+
+    // "prefix" is only a keyword at the start of a decl
+    let s = "struct A { prefix: u8 }";
+    parse(s, None).unwrap();
+}
+
 #[test]
 fn parse_return_types() {
     let s = "\
@@ -249,6 +1293,164 @@ fn parse_const() {
     parse(s, None).unwrap();
 }
 
+#[test]
+fn parse_enum() {
+    let s = "enum status: u32 {}";
+    parse(s, None).unwrap();
+
+    let s = "\
+      enum status: u8 {
+        SUCCESS = 0,
+        ERR_AGAIN = 1,
+        ERR_UNKNOWN = 255,
+      }
+      struct res {
+        st: status,
+      }
+      fn(v1) foo(st: status) -> status;
+     ";
+    parse(s, None).unwrap();
+}
+
+#[test]
+fn parse_enum_fail() {
+    // Underlying type is required
+    let s = "enum status { A = 0 }";
+    parse(s, None).err().unwrap();
+
+    // Underlying type must be an integer
+    let s = "enum status: foo { A = 0 }";
+    parse(s, None).err().unwrap();
+
+    // Discriminants are required
+    let s = "enum status: u8 { A }";
+    parse(s, None).err().unwrap();
+
+    // Discriminants must fit the underlying type
+    let s = "enum status: u8 { A = 256 }";
+    parse(s, None).err().unwrap();
+
+    // Enumerators must be unique
+    let s = "enum status: u8 { A = 0, A = 1 }";
+    parse(s, None).err().unwrap();
+
+    // Negative discriminants need a signed type
+    let s = "enum status: i8 { A = -1, B = 0x7f }";
+    parse(s, None).unwrap();
+    let s = "enum status: u8 { A = -1 }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_flags() {
+    let s = "\
+      flags opts: u32 {
+        NONBLOCK = 0x1,
+        ZEROCOPY = 0x2,
+        ALL = 3,
+      }
+      struct cfg {
+        flags: opts,
+      }
+      fn(v1) foo(flags: opts);
+     ";
+    parse(s, None).unwrap();
+
+    let s = "flags opts: u8 { BIG = 0x100 }";
+    parse(s, None).err().unwrap();
+
+    let s = "flags opts: u8 { A = 0x1, A = 0x2 }";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_typed_const() {
+    let s = "\
+      const A: u16 = 0x5DC;
+      const B: i32 = -5;
+      const C: i8 = -128;
+      const D: u64 = 18446744073709551615;
+      const E = \"example\";
+     ";
+    parse(s, None).unwrap();
+
+    // out of range
+    let s = "const A: u8 = 256;";
+    parse(s, None).err().unwrap();
+    let s = "const A: i8 = -129;";
+    parse(s, None).err().unwrap();
+    let s = "const A: u32 = -1;";
+    parse(s, None).err().unwrap();
+    let s = "const A = -1;";
+    parse(s, None).err().unwrap();
+
+    // only integer types
+    let s = "const A: foo = 1;";
+    parse(s, None).err().unwrap();
+    let s = "const A: u8 = \"x\";";
+    parse(s, None).err().unwrap();
+}
+
+#[test]
+fn parse_const_expr() {
+    let s = "\
+      const MAX_PKT: u16 = 1500;
+      const HDR: u8 = 14;
+      const TOTAL: u32 = MAX_PKT + HDR * 2;
+      const MASK: u64 = (1 << 40) - 1 | 0xf & 3;
+      const NEG: i32 = -(HDR - 20) / 2;
+      struct packet {
+        dat: [u8; MAX_PKT],
+        hdr: [u8; (HDR + 2) * 4],
+      }
+     ";
+    let api = parse(s, None).unwrap();
+    let val = |name: &str| match api.symbols[name].as_ref() {
+        Decl::Const(ConstDecl {
+            val: ConstValue::Int(_, expr),
+            ..
+        }) => expr.val,
+        _ => panic!("not an int const"),
+    };
+    assert_eq!(val("TOTAL"), 1528);
+    assert_eq!(val("MASK"), ((1 << 40) - 1) | (0xf & 3));
+    assert_eq!(val("NEG"), 3);
+}
+
+#[test]
+fn parse_const_expr_fail() {
+    // overflow of the declared type, including intermediates
+    let s = "const A: u8 = 200 + 100;";
+    parse(s, None).err().unwrap();
+    let s = "const A: u8 = 200 + 100 - 100;";
+    parse(s, None).err().unwrap();
+    let s = "const A: u8 = 2 - 3;";
+    parse(s, None).err().unwrap();
+
+    // referenced constants must fit the declared type
+    let s = "const B = 300; const A: u8 = B - 100;";
+    parse(s, None).err().unwrap();
+
+    // undefined behaviour in C
+    let s = "const A = 1 / 0;";
+    parse(s, None).err().unwrap();
+    let s = "const A = 1 << 64;";
+    parse(s, None).err().unwrap();
+    let s = "const A: i32 = -1 << 2;";
+    parse(s, None).err().unwrap();
+
+    // names must be previously defined integer constants
+    let s = "struct A { a: [u8; N] } const N = 4;";
+    parse(s, None).err().unwrap();
+    let s = "const N = \"x\"; struct A { a: [u8; N] }";
+    parse(s, None).err().unwrap();
+    let s = "struct N {} struct A { a: [u8; N] }";
+    parse(s, None).err().unwrap();
+
+    let s = "const A = (1 + 2;";
+    parse(s, None).err().unwrap();
+}
+
 #[test]
 fn parse_fail_duplicate_symbol() {
     let s = "const FOO = 45;const FOO = 67;";